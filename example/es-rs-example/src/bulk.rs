@@ -0,0 +1,438 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::BulkParts;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::task::JoinSet;
+
+use crate::{
+    client::EsClient,
+    error::{self, ErrorCause, EsError},
+    retry::Operation,
+};
+
+/// Limits for a single `_bulk` request and for the requests in flight
+#[derive(Debug, Clone, Copy)]
+pub struct BulkOptions {
+    /// Maximum number of documents per request
+    pub max_docs: usize,
+    /// Maximum request body size in bytes. A single larger document is sent on its own.
+    pub max_bytes: usize,
+    /// Maximum number of requests in flight
+    pub concurrency: usize,
+}
+
+impl Default for BulkOptions {
+    fn default() -> Self {
+        BulkOptions {
+            max_docs: 500,
+            max_bytes: 5 * 1024 * 1024,
+            concurrency: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BulkItem {
+    /// Index `source`, with an id generated by Elasticsearch when `id` is `None`
+    Index {
+        id: Option<String>,
+        source: Value,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+impl BulkItem {
+    fn id(&self) -> Option<&str> {
+        match self {
+            BulkItem::Index { id, .. } => id.as_deref(),
+            BulkItem::Delete { id } => Some(id),
+        }
+    }
+
+    /// The action line and the optional source line
+    fn lines(&self) -> Result<Vec<String>> {
+        let lines = match self {
+            BulkItem::Index {
+                id: Some(id),
+                source,
+            } => vec![
+                json!({ "index": { "_id": id } }).to_string(),
+                serde_json::to_string(source)?,
+            ],
+            BulkItem::Index { id: None, source } => {
+                vec![
+                    json!({ "index": {} }).to_string(),
+                    serde_json::to_string(source)?,
+                ]
+            }
+            BulkItem::Delete { id } => vec![json!({ "delete": { "_id": id } }).to_string()],
+        };
+        Ok(lines)
+    }
+}
+
+/// An item Elasticsearch rejected, `position` is its index in the input
+#[derive(Debug)]
+pub struct BulkFailure {
+    pub position: usize,
+    pub id: Option<String>,
+    pub status: u16,
+    pub cause: ErrorCause,
+}
+
+#[derive(Debug, Default)]
+pub struct BulkReport {
+    pub succeeded: usize,
+    pub failures: Vec<BulkFailure>,
+}
+
+impl BulkReport {
+    pub fn print_failures(&self) {
+        for failure in &self.failures {
+            eprintln!(
+                "  #{} {} (status {}): {}: {}",
+                failure.position,
+                failure.id.as_deref().unwrap_or("-"),
+                failure.status,
+                failure.cause.error_type,
+                failure.cause.reason.as_deref().unwrap_or("")
+            );
+        }
+    }
+
+    fn merge(&mut self, other: BulkReport) {
+        self.succeeded += other.succeeded;
+        self.failures.extend(other.failures);
+    }
+}
+
+/// An item serialized once, ready to be sent as many times as it is retried
+struct Encoded {
+    position: usize,
+    id: Option<String>,
+    lines: Vec<String>,
+}
+
+impl Encoded {
+    /// Size on the wire, every line is followed by a newline
+    fn bytes(&self) -> usize {
+        self.lines.iter().map(|line| line.len() + 1).sum()
+    }
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    items: Vec<HashMap<String, ItemResult>>,
+}
+
+#[derive(Deserialize)]
+struct ItemResult {
+    status: u16,
+    #[serde(default)]
+    error: Option<ErrorCause>,
+}
+
+/// Send `items` to `index_name` through `_bulk`, batched by `options`.
+/// Items rejected with 429 are retried with backoff, every other item
+/// failure ends up in the report. A request that fails as a whole, e.g. with
+/// 413 or because no node can be reached, fails each of its items while the
+/// other batches carry on.
+pub async fn send_all<I>(
+    client: &EsClient,
+    index_name: &str,
+    items: I,
+    options: BulkOptions,
+) -> Result<BulkReport>
+where
+    I: IntoIterator<Item = BulkItem>,
+{
+    let index_name: Arc<str> = Arc::from(index_name);
+    let concurrency = options.concurrency.max(1);
+    let mut report = BulkReport::default();
+    let mut in_flight = JoinSet::new();
+
+    let mut batch: Vec<Encoded> = Vec::new();
+    let mut batch_bytes = 0;
+    for (position, item) in items.into_iter().enumerate() {
+        let encoded = Encoded {
+            position,
+            id: item.id().map(str::to_string),
+            lines: item.lines()?,
+        };
+
+        let bytes = encoded.bytes();
+        if !batch.is_empty()
+            && (batch.len() >= options.max_docs || batch_bytes + bytes > options.max_bytes)
+        {
+            // Wait for a free slot before building more batches
+            while in_flight.len() >= concurrency {
+                report.merge(join_next(&mut in_flight).await?);
+            }
+            let full = std::mem::take(&mut batch);
+            in_flight.spawn(send_batch(client.clone(), index_name.clone(), full));
+            batch_bytes = 0;
+        }
+        batch_bytes += bytes;
+        batch.push(encoded);
+    }
+    if !batch.is_empty() {
+        in_flight.spawn(send_batch(client.clone(), index_name.clone(), batch));
+    }
+
+    while !in_flight.is_empty() {
+        report.merge(join_next(&mut in_flight).await?);
+    }
+    report.failures.sort_by_key(|failure| failure.position);
+    Ok(report)
+}
+
+async fn join_next(in_flight: &mut JoinSet<BulkReport>) -> Result<BulkReport> {
+    match in_flight.join_next().await {
+        Some(result) => result.context("Bulk task panicked"),
+        None => Ok(BulkReport::default()),
+    }
+}
+
+/// Send one batch. When the request itself fails, every item still pending
+/// is reported as failed with the request error as its cause.
+async fn send_batch(client: EsClient, index_name: Arc<str>, batch: Vec<Encoded>) -> BulkReport {
+    let mut report = BulkReport::default();
+    let mut pending = batch;
+    if let Err(err) = resend_rejected(&client, &index_name, &mut pending, &mut report).await {
+        let status = match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<EsError>())
+        {
+            Some(EsError::Api { status, .. }) => *status,
+            Some(EsError::NotFound { .. }) => 404,
+            _ => 0,
+        };
+        let cause = ErrorCause::new("bulk_request_failed", format!("{:#}", err));
+        report
+            .failures
+            .extend(pending.into_iter().map(|item| BulkFailure {
+                position: item.position,
+                id: item.id,
+                status,
+                cause: cause.clone(),
+            }));
+    }
+    report
+}
+
+/// Send `pending`, resending the items rejected with 429 until they succeed
+/// or the client's retry policy runs out of attempts. Items leave `pending`
+/// once their outcome is in `report`.
+async fn resend_rejected(
+    client: &EsClient,
+    index_name: &str,
+    pending: &mut Vec<Encoded>,
+    report: &mut BulkReport,
+) -> Result<()> {
+    let policy = client.retry_policy();
+    let mut attempt = 1;
+
+    loop {
+        let lines: Vec<&str> = pending
+            .iter()
+            .flat_map(|item| item.lines.iter().map(String::as_str))
+            .collect();
+        let lines = &lines;
+
+        // Retries are handled here, per item, rather than by the client
+        let response = client
+            .send(Operation::NonIdempotent, |es| async move {
+                es.bulk(BulkParts::Index(index_name))
+                    .body(lines.clone())
+                    .send()
+                    .await
+            })
+            .await?;
+
+        if response.status_code() == 429 && attempt < policy.max_attempts {
+            let delay = policy
+                .delay_for(&response, attempt)
+                .unwrap_or_else(|| policy.backoff(attempt));
+            eprintln!(
+                "Bulk request of {} items rejected (429), retrying in {:?}",
+                pending.len(),
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            continue;
+        }
+
+        let response = error::check(response)
+            .await
+            .with_context(|| format!("Bulk request to '{}' failed", index_name))?;
+        let body: BulkResponse = response.json().await?;
+
+        let rejected = record_results(pending, body.items, attempt < policy.max_attempts, report)?;
+
+        if rejected.is_empty() {
+            return Ok(());
+        }
+        let delay = policy.backoff(attempt);
+        eprintln!(
+            "{} items rejected (429), retrying in {:?}",
+            rejected.len(),
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        *pending = rejected;
+        attempt += 1;
+    }
+}
+
+/// Count the outcome of every item of `sent` in `report` and return the items
+/// rejected with 429 that should be sent again. `sent` is left untouched when
+/// the results cannot be matched to it.
+fn record_results(
+    sent: &mut Vec<Encoded>,
+    results: Vec<HashMap<String, ItemResult>>,
+    retry_rejected: bool,
+    report: &mut BulkReport,
+) -> Result<Vec<Encoded>> {
+    // Results are matched to items by position, they cannot be attributed otherwise
+    if results.len() != sent.len() {
+        bail!(
+            "Bulk response has {} results for {} items",
+            results.len(),
+            sent.len()
+        );
+    }
+
+    let mut rejected = Vec::new();
+    for (item, result) in sent.drain(..).zip(results) {
+        let Some(result) = result.into_values().next() else {
+            report.failures.push(BulkFailure {
+                position: item.position,
+                id: item.id,
+                status: 0,
+                cause: ErrorCause::new(
+                    "missing_result",
+                    "the bulk response has an empty entry for this item".to_string(),
+                ),
+            });
+            continue;
+        };
+        match result.error {
+            None => report.succeeded += 1,
+            Some(_) if result.status == 429 && retry_rejected => rejected.push(item),
+            Some(cause) => report.failures.push(BulkFailure {
+                position: item.position,
+                id: item.id,
+                status: result.status,
+                cause,
+            }),
+        }
+    }
+    Ok(rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::tests::{closed_node, config};
+
+    fn encoded(count: usize) -> Vec<Encoded> {
+        (0..count)
+            .map(|position| Encoded {
+                position,
+                id: Some(format!("doc-{}", position)),
+                lines: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn results(value: Value) -> Vec<HashMap<String, ItemResult>> {
+        serde_json::from_value::<BulkResponse>(json!({ "items": value }))
+            .unwrap()
+            .items
+    }
+
+    #[test]
+    fn every_item_is_counted() {
+        let items = results(json!([
+            { "index": { "status": 201 } },
+            { "index": { "status": 400, "error": { "type": "mapper_parsing_exception" } } },
+            { "index": { "status": 429, "error": { "type": "es_rejected_execution_exception" } } },
+            {},
+            { "delete": { "status": 200 } }
+        ]));
+        let mut report = BulkReport::default();
+        let rejected = record_results(&mut encoded(5), items, true, &mut report).unwrap();
+
+        assert_eq!(report.succeeded, 2);
+        let failed: Vec<(usize, u16)> = report
+            .failures
+            .iter()
+            .map(|failure| (failure.position, failure.status))
+            .collect();
+        assert_eq!(failed, [(1, 400), (3, 0)]);
+        assert_eq!(report.failures[1].cause.error_type, "missing_result");
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].position, 2);
+    }
+
+    #[test]
+    fn rejected_items_fail_once_retries_run_out() {
+        let items = results(json!([
+            { "index": { "status": 429, "error": { "type": "es_rejected_execution_exception" } } }
+        ]));
+        let mut report = BulkReport::default();
+        let rejected = record_results(&mut encoded(1), items, false, &mut report).unwrap();
+
+        assert!(rejected.is_empty());
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].status, 429);
+    }
+
+    #[test]
+    fn missing_results_fail_the_batch() {
+        let items = results(json!([{ "index": { "status": 201 } }]));
+        let mut report = BulkReport::default();
+        let mut sent = encoded(2);
+
+        assert!(record_results(&mut sent, items, true, &mut report).is_err());
+        assert_eq!(sent.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_requests_fail_their_items_and_keep_the_report() {
+        let mut config = config(&[&closed_node().await]);
+        config.retry.max_attempts = 1;
+        let client = EsClient::connect(&config).await.unwrap();
+        let items = (0..3).map(|position| BulkItem::Delete {
+            id: format!("doc-{}", position),
+        });
+        let options = BulkOptions {
+            max_docs: 1,
+            ..BulkOptions::default()
+        };
+
+        let report = send_all(&client, "docs", items, options).await.unwrap();
+
+        assert_eq!(report.succeeded, 0);
+        let failed: Vec<(usize, Option<&str>)> = report
+            .failures
+            .iter()
+            .map(|failure| (failure.position, failure.id.as_deref()))
+            .collect();
+        assert_eq!(
+            failed,
+            [(0, Some("doc-0")), (1, Some("doc-1")), (2, Some("doc-2"))]
+        );
+        assert!(
+            report
+                .failures
+                .iter()
+                .all(|failure| failure.cause.error_type == "bulk_request_failed")
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{error::Error as _, future::Future, sync::Arc};
+
+use anyhow::{Context, Result};
+use elasticsearch::{
+    Elasticsearch,
+    auth::{ClientCertificate, Credentials},
+    cert::{Certificate, CertificateValidation},
+    http::{
+        Url,
+        headers::{AUTHORIZATION, HeaderValue},
+        response::Response,
+        transport::{SingleNodeConnectionPool, TransportBuilder},
+    },
+};
+
+use crate::{
+    config::{Auth, Config, TlsConfig},
+    pool::NodePool,
+    retry::{Operation, RetryPolicy},
+};
+
+/// Client that spreads requests over a pool of nodes, fails over to the
+/// next node when one cannot be reached and retries transient failures
+#[derive(Clone)]
+pub struct EsClient {
+    pool: Arc<NodePool>,
+    retry: RetryPolicy,
+}
+
+impl EsClient {
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let pool = NodePool::new(config)?;
+        pool.sniff_if_due().await;
+
+        Ok(EsClient {
+            pool: Arc::new(pool),
+            retry: config.retry.clone(),
+        })
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Run `request`, retrying 429/502/503/504 responses and dropped
+    /// connections with backoff when `operation` can safely be repeated
+    pub async fn send<F, Fut>(
+        &self,
+        operation: Operation,
+        request: F,
+    ) -> Result<Response, elasticsearch::Error>
+    where
+        F: Fn(Elasticsearch) -> Fut,
+        Fut: Future<Output = Result<Response, elasticsearch::Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = self.send_once(&request).await;
+            if !operation.is_retryable() || attempt >= self.retry.max_attempts {
+                return result;
+            }
+
+            let delay = match &result {
+                Ok(response) => match self.retry.delay_for(response, attempt) {
+                    Some(delay) => delay,
+                    None => return result,
+                },
+                Err(err) if is_transient_error(err) => self.retry.backoff(attempt),
+                Err(_) => return result,
+            };
+            let reason = match &result {
+                Ok(response) => response.status_code().to_string(),
+                Err(err) => err.to_string(),
+            };
+            eprintln!(
+                "Request failed ({}), retrying in {:?} (attempt {}/{})",
+                reason, delay, attempt, self.retry.max_attempts
+            );
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Run `request` against a live node. Connection failures mark the node
+    /// dead and move on to the next one, every other outcome is returned as is.
+    /// Failing over is safe for any request because it never reached a node.
+    async fn send_once<F, Fut>(&self, request: &F) -> Result<Response, elasticsearch::Error>
+    where
+        F: Fn(Elasticsearch) -> Fut,
+        Fut: Future<Output = Result<Response, elasticsearch::Error>>,
+    {
+        self.pool.sniff_if_due().await;
+
+        let mut attempts = self.pool.len();
+        loop {
+            let node = self.pool.select();
+            match request(node.client.clone()).await {
+                Err(err) if is_connect_error(&err) && attempts > 1 => {
+                    self.pool.mark_dead(&node);
+                    attempts -= 1;
+                }
+                Err(err) => {
+                    if is_connect_error(&err) {
+                        self.pool.mark_dead(&node);
+                    }
+                    return Err(err);
+                }
+                Ok(response) => {
+                    self.pool.mark_alive(&node);
+                    return Ok(response);
+                }
+            }
+        }
+    }
+}
+
+fn http_error(err: &elasticsearch::Error) -> Option<&reqwest::Error> {
+    err.source()
+        .and_then(|source| source.downcast_ref::<reqwest::Error>())
+}
+
+fn is_connect_error(err: &elasticsearch::Error) -> bool {
+    http_error(err).is_some_and(reqwest::Error::is_connect)
+}
+
+/// Timeouts, refused and reset connections. Errors building the request or
+/// decoding the response would fail the same way on every attempt.
+fn is_transient_error(err: &elasticsearch::Error) -> bool {
+    err.is_timeout() || http_error(err).is_some_and(|err| err.is_connect() || err.is_request())
+}
+
+/// Build a client bound to the single node at `url`
+pub fn node_client(url: Url, config: &Config) -> Result<Elasticsearch> {
+    let mut builder = TransportBuilder::new(SingleNodeConnectionPool::new(url));
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder = with_auth(builder, config.auth.as_ref())?;
+    builder = with_tls(builder, &config.tls)?;
+
+    Ok(Elasticsearch::new(builder.build()?))
+}
+
+fn with_auth(builder: TransportBuilder, auth: Option<&Auth>) -> Result<TransportBuilder> {
+    let builder = match auth {
+        None => builder,
+        Some(Auth::Basic { username, password }) => {
+            builder.auth(Credentials::Basic(username.clone(), password.clone()))
+        }
+        Some(Auth::ApiKey(key)) => match key.split_once(':') {
+            Some((id, key)) => builder.auth(Credentials::ApiKey(id.to_string(), key.to_string())),
+            // Already encoded keys are passed through untouched
+            None => builder.header(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("ApiKey {}", key))
+                    .context("API key contains invalid characters")?,
+            ),
+        },
+        Some(Auth::Bearer(token)) => builder.auth(Credentials::Bearer(token.clone())),
+    };
+
+    Ok(builder)
+}
+
+fn with_tls(mut builder: TransportBuilder, tls: &TlsConfig) -> Result<TransportBuilder> {
+    if let Some(path) = &tls.client_cert {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read client certificate {}", path.display()))?;
+        builder = builder.auth(Credentials::Certificate(ClientCertificate::Pkcs12(
+            bytes,
+            tls.client_cert_password.clone(),
+        )));
+    }
+
+    if tls.insecure {
+        builder = builder.cert_validation(CertificateValidation::None);
+    } else if let Some(path) = &tls.ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+        let cert = Certificate::from_pem(&pem)
+            .with_context(|| format!("Invalid CA certificate {}", path.display()))?;
+        builder = builder.cert_validation(CertificateValidation::Full(cert));
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::pool::tests::{closed_node, config, mock_node};
+
+    #[tokio::test]
+    async fn unreachable_nodes_fail_over_to_live_ones() {
+        let dead = closed_node().await;
+        let (live, served) = mock_node("live").await;
+        let client = EsClient::connect(&config(&[&dead, &live])).await.unwrap();
+
+        // Every request lands on the live node, whichever node is picked first
+        for _ in 0..3 {
+            let response = client
+                .send(Operation::Read, |es| async move { es.info().send().await })
+                .await
+                .unwrap();
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["name"], "live");
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn connect_errors_surface_when_every_node_is_down() {
+        let (first, second) = (closed_node().await, closed_node().await);
+        let mut config = config(&[&first, &second]);
+        config.retry.max_attempts = 1;
+        let client = EsClient::connect(&config).await.unwrap();
+
+        let err = client
+            .send(Operation::Read, |es| async move { es.info().send().await })
+            .await
+            .unwrap_err();
+        assert!(is_connect_error(&err));
+    }
+}
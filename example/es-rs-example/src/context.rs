@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    client::EsClient,
+    embed::{self, Embedder},
+    search::{self, Filters, HybridQuery, RrfParams, SearchHit, SearchQuery},
+    tokenizer::Tokenizer,
+};
+
+pub const DEFAULT_MAX_TOKENS: usize = 10_000;
+
+/// Chunks ranked per token of budget, enough to fill it after duplicates are dropped
+const TOKENS_PER_CANDIDATE: usize = 100;
+const MIN_CANDIDATES: usize = 10;
+const MAX_CANDIDATES: usize = 200;
+
+/// Shortest repeated text treated as the overlap between two pieces of a section
+const MIN_OVERLAP: usize = 20;
+
+const SEPARATOR: &str = "\n\n---\n\n";
+
+#[derive(Debug, Clone)]
+pub struct ContextQuery {
+    pub text: String,
+    pub filters: Filters,
+    /// Budget for the whole context, headers and separators included
+    pub max_tokens: usize,
+    /// Fusion of the rankings when an embedder is given
+    pub rrf: RrfParams,
+}
+
+/// One packed chunk
+#[derive(Debug, Clone, Serialize)]
+pub struct ContextSection {
+    pub heading: String,
+    pub source: String,
+    pub score: Option<f64>,
+    pub tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Context {
+    /// The sections as Markdown, best first
+    pub text: String,
+    pub tokens: usize,
+    pub sections: Vec<ContextSection>,
+    /// Chunks left out as duplicates of packed ones
+    pub duplicates: usize,
+    /// Chunks left out because they did not fit the budget
+    pub truncated: usize,
+}
+
+/// Rank chunks for `query`, hybrid when an embedder is given, and pack the best ones
+/// into `query.max_tokens` tokens as counted by `tokenizer`
+pub async fn retrieve(
+    client: &EsClient,
+    index_name: &str,
+    embedder: Option<&dyn Embedder>,
+    tokenizer: &dyn Tokenizer,
+    query: &ContextQuery,
+) -> Result<Context> {
+    let candidates =
+        (query.max_tokens / TOKENS_PER_CANDIDATE).clamp(MIN_CANDIDATES, MAX_CANDIDATES);
+    let results = match embedder {
+        Some(embedder) => {
+            let hybrid = HybridQuery {
+                text: query.text.clone(),
+                vector: embed::embed_query(embedder, &query.text).await?,
+                filters: query.filters.clone(),
+                size: candidates,
+                window: None,
+                rrf: query.rrf,
+            };
+            search::hybrid(client, index_name, &hybrid).await?
+        }
+        None => {
+            let lexical = SearchQuery {
+                text: query.text.clone(),
+                filters: query.filters.clone(),
+                date_interval: None,
+                from: 0,
+                size: candidates as i64,
+                search_after: None,
+            };
+            search::search(client, index_name, &lexical).await?
+        }
+    };
+
+    Ok(assemble(&results.hits, tokenizer, query.max_tokens))
+}
+
+/// Greedily pack `hits`, best first, into `max_tokens`. A chunk that does not
+/// fit is skipped and smaller ones after it are still tried. Chunks repeating
+/// a packed one are dropped, and the overlap between two pieces of one section
+/// is only kept once.
+pub fn assemble(hits: &[SearchHit], tokenizer: &dyn Tokenizer, max_tokens: usize) -> Context {
+    let separator_tokens = tokenizer.count(SEPARATOR);
+    let mut packed: Vec<(&SearchHit, String)> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut context = Context {
+        text: String::new(),
+        tokens: 0,
+        sections: Vec::new(),
+        duplicates: 0,
+        truncated: 0,
+    };
+
+    for hit in hits {
+        let content = hit.content.trim();
+        if !seen.insert(content) || packed.iter().any(|(_, body)| body.contains(content)) {
+            context.duplicates += 1;
+            continue;
+        }
+        let body = packed
+            .iter()
+            .filter(|(other, _)| same_section(hit, other))
+            .fold(content, |body, (other, _)| {
+                strip_overlap(&other.content, body)
+            })
+            .to_string();
+
+        let heading = section_heading(hit);
+        let source = source_link(hit);
+        let block = format!("## {}\nSource: {}\n\n{}", heading, source, body);
+        let tokens = tokenizer.count(&block);
+        let cost = if packed.is_empty() {
+            tokens
+        } else {
+            tokens + separator_tokens
+        };
+        if context.tokens + cost > max_tokens {
+            context.truncated += 1;
+            continue;
+        }
+
+        if !packed.is_empty() {
+            context.text.push_str(SEPARATOR);
+        }
+        context.text.push_str(&block);
+        context.tokens += cost;
+        context.sections.push(ContextSection {
+            heading,
+            source,
+            score: hit.score,
+            tokens,
+        });
+        packed.push((hit, body));
+    }
+
+    context
+}
+
+/// Pieces of one long section, cut by the splitter with an overlap
+fn same_section(a: &SearchHit, b: &SearchHit) -> bool {
+    a.repo == b.repo
+        && a.version == b.version
+        && a.git_ref == b.git_ref
+        && a.path == b.path
+        && a.heading_path == b.heading_path
+}
+
+/// `next` without the longest prefix that `previous` ends with
+fn strip_overlap<'a>(previous: &str, next: &'a str) -> &'a str {
+    let previous = previous.trim_end();
+    let longest = previous.len().min(next.len());
+    next.char_indices()
+        .map(|(i, _)| i)
+        .chain([next.len()])
+        .filter(|i| (MIN_OVERLAP..=longest).contains(i))
+        .rev()
+        .find(|&i| previous.ends_with(&next[..i]))
+        .map_or(next, |i| next[i..].trim_start())
+}
+
+fn section_heading(hit: &SearchHit) -> String {
+    if hit.heading_path.is_empty() {
+        hit.title.clone()
+    } else {
+        hit.heading_path.join(" > ")
+    }
+}
+
+/// `repo@ref/path#anchor`, the commit when the chunk was read from git
+fn source_link(hit: &SearchHit) -> String {
+    let at = hit
+        .commit
+        .as_deref()
+        .or(hit.git_ref.as_deref())
+        .or(hit.version.as_deref());
+    let mut link = match at {
+        Some(at) => format!("{}@{}/{}", hit.repo, at, hit.path),
+        None => format!("{}/{}", hit.repo, hit.path),
+    };
+    if let Some(heading) = hit.heading_path.last() {
+        link.push('#');
+        link.push_str(&anchor(heading));
+    }
+    link
+}
+
+/// GitHub style anchor of a heading, `Bulk API (v2)` -> `bulk-api-v2`
+fn anchor(heading: &str) -> String {
+    heading
+        .trim()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            '-' | '_' => Some(c),
+            c if c.is_alphanumeric() => Some(c.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::tests::hit;
+
+    /// One token per word, so budgets are easy to count
+    #[derive(Debug)]
+    struct Words;
+
+    impl Tokenizer for Words {
+        fn count(&self, text: &str) -> usize {
+            text.split_whitespace().count()
+        }
+    }
+
+    fn block_tokens(hit: &SearchHit) -> usize {
+        let block = format!(
+            "## {}\nSource: {}\n\n{}",
+            section_heading(hit),
+            source_link(hit),
+            hit.content
+        );
+        Words.count(&block)
+    }
+
+    #[test]
+    fn packs_best_hits_that_fit_the_budget() {
+        let hits = [
+            hit("a", "first best chunk"),
+            hit("b", &"long ".repeat(50)),
+            hit("c", "small one"),
+        ];
+        let budget = block_tokens(&hits[0]) + Words.count(SEPARATOR) + block_tokens(&hits[2]);
+        let context = assemble(&hits, &Words, budget);
+
+        let sources: Vec<&str> = context.sections.iter().map(|s| s.source.as_str()).collect();
+        assert_eq!(sources, ["docs/a.md", "docs/c.md"]);
+        assert_eq!(context.truncated, 1);
+        assert_eq!(context.tokens, budget);
+        assert_eq!(Words.count(&context.text), context.tokens);
+        assert!(
+            context
+                .text
+                .starts_with("## a\nSource: docs/a.md\n\nfirst best chunk")
+        );
+    }
+
+    #[test]
+    fn nothing_fits_an_empty_budget() {
+        let context = assemble(&[hit("a", "text")], &Words, 0);
+        assert!(context.sections.is_empty());
+        assert!(context.text.is_empty());
+        assert_eq!(context.truncated, 1);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let hits = [
+            hit("a", "The bulk API indexes many documents in one request."),
+            hit(
+                "b",
+                "  The bulk API indexes many documents in one request.\n",
+            ),
+            hit("c", "many documents"),
+            hit("d", "Something else entirely"),
+        ];
+        let context = assemble(&hits, &Words, 1000);
+
+        assert_eq!(context.sections.len(), 2);
+        assert_eq!(context.duplicates, 2);
+        assert_eq!(context.sections[1].source, "docs/d.md");
+    }
+
+    #[test]
+    fn overlap_between_pieces_of_a_section_is_kept_once() {
+        let overlap = "repeated at the end and the start";
+        let first = hit("a", &format!("Opening words of the section, {}", overlap));
+        let mut second = hit("a", &format!("{} then the rest of it.", overlap));
+        second.id = "a-1".to_string();
+        let context = assemble(&[first, second], &Words, 1000);
+
+        assert_eq!(context.sections.len(), 2);
+        assert_eq!(context.text.matches(overlap).count(), 1);
+        assert!(context.text.ends_with("\n\nthen the rest of it."));
+    }
+
+    #[test]
+    fn strip_overlap_ignores_short_repeats() {
+        assert_eq!(
+            strip_overlap("ends with the", "the next piece"),
+            "the next piece"
+        );
+        assert_eq!(
+            strip_overlap(
+                "first ... a long enough repeat",
+                "a long enough repeat and more"
+            ),
+            "and more"
+        );
+        assert_eq!(
+            strip_overlap("unrelated text here", "different words"),
+            "different words"
+        );
+    }
+
+    #[test]
+    fn source_links_point_at_the_commit_and_heading() {
+        let mut chunk = hit("guide", "");
+        chunk.git_ref = Some("v1.2.0".to_string());
+        chunk.commit = Some("abc123".to_string());
+        chunk.heading_path = vec!["Guide".to_string(), "Bulk API (v2)".to_string()];
+
+        assert_eq!(source_link(&chunk), "docs@abc123/guide.md#bulk-api-v2");
+        assert_eq!(section_heading(&chunk), "Guide > Bulk API (v2)");
+    }
+}
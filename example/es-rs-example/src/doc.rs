@@ -0,0 +1,91 @@
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use elasticsearch::{DeleteParts, GetParts, IndexParts};
+use serde_json::Value;
+
+use crate::{
+    client::EsClient,
+    error::{self, EsError},
+    retry::Operation,
+};
+
+pub async fn put(
+    client: &EsClient,
+    index_name: &str,
+    id: &str,
+    body: Option<String>,
+) -> Result<()> {
+    // Fall back to stdin so documents can be piped in from scripts
+    let raw = match body {
+        Some(body) => body,
+        None => {
+            let mut buf = String::new();
+            std::io::stdin().read_to_string(&mut buf)?;
+            buf
+        }
+    };
+    let document: Value = serde_json::from_str(&raw).context("Document body is not valid JSON")?;
+    let document = &document;
+
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.index(IndexParts::IndexId(index_name, id))
+                .body(document)
+                .send()
+                .await
+        })
+        .await?;
+
+    let response = error::check(response)
+        .await
+        .context("Failed to index document")?;
+
+    let body: Value = response.json().await?;
+    println!(
+        "Document '{}' {} in '{}'",
+        id,
+        body["result"].as_str().unwrap_or("indexed"),
+        index_name
+    );
+    Ok(())
+}
+
+pub async fn get(client: &EsClient, index_name: &str, id: &str) -> Result<()> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.get(GetParts::IndexId(index_name, id)).send().await
+        })
+        .await?;
+
+    if response.status_code() == 404 {
+        let reason = format!("document '{}' not found in '{}'", id, index_name);
+        return Err(EsError::not_found(reason).into());
+    }
+    let response = error::check(response)
+        .await
+        .context("Failed to get document")?;
+
+    let body: Value = response.json().await?;
+    println!("{}", serde_json::to_string_pretty(&body["_source"])?);
+    Ok(())
+}
+
+pub async fn delete(client: &EsClient, index_name: &str, id: &str) -> Result<()> {
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.delete(DeleteParts::IndexId(index_name, id)).send().await
+        })
+        .await?;
+
+    if response.status_code() == 404 {
+        let reason = format!("document '{}' not found in '{}'", id, index_name);
+        return Err(EsError::not_found(reason).into());
+    }
+    error::check(response)
+        .await
+        .context("Failed to delete document")?;
+
+    println!("Deleted document '{}' from '{}'", id, index_name);
+    Ok(())
+}
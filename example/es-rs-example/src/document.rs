@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::schema::Mappings;
+
+/// A Rust type stored as an Elasticsearch document. The mapping is derived
+/// from the same field list as the serde implementation, so the two cannot
+/// drift apart. Implement it with `es_document!`.
+pub trait EsDocument: Serialize + DeserializeOwned {
+    fn mappings() -> Mappings;
+}
+
+/// Declare a document struct together with its `EsDocument` implementation.
+/// Every field names its mapping type after `=>`, using the `FieldType`
+/// variant names:
+///
+/// ```ignore
+/// es_document! {
+///     pub struct Article {
+///         pub title: String => Text,
+///         pub tags: Vec<String> => Keyword,
+///     }
+/// }
+/// ```
+///
+/// Fields are mapped under their Rust name, so `#[serde(rename)]` must not be used.
+macro_rules! es_document {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $ty:ty => $kind:ident
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $crate::document::EsDocument for $name {
+            fn mappings() -> $crate::schema::Mappings {
+                let mut properties = std::collections::BTreeMap::new();
+                $(
+                    properties.insert(
+                        stringify!($field).to_string(),
+                        $crate::schema::FieldMapping::new($crate::schema::FieldType::$kind),
+                    );
+                )*
+                $crate::schema::Mappings {
+                    properties,
+                    extra: std::collections::BTreeMap::new(),
+                }
+            }
+        }
+    };
+}
+
+es_document! {
+    /// A section of documentation, the unit that is indexed and searched
+    pub struct DocChunk {
+        /// Copy of the document id. `_id` cannot be sorted on, this field is
+        /// the tiebreaker for `search_after` pagination.
+        pub chunk_id: String => Keyword,
+        pub title: String => Text,
+        pub content: String => Text,
+        pub date: DateTime<Utc> => Date,
+        pub tags: Vec<String> => Keyword,
+        /// Repository the chunk was ingested from
+        pub repo: String => Keyword,
+        /// Tag, branch or commit the chunk was read at, `None` for a working tree
+        pub git_ref: Option<String> => Keyword,
+        /// Commit SHA `git_ref` resolved to when the chunk was last indexed.
+        /// Chunks that did not change keep the commit they were first read at.
+        pub commit: Option<String> => Keyword,
+        /// Library version the chunk documents, e.g. `1.2.0`
+        pub version: Option<String> => Keyword,
+        /// Source file, relative to the ingested directory
+        pub path: String => Keyword,
+        /// Headings the chunk sits under, outermost first
+        pub heading_path: Vec<String> => Keyword,
+        /// Hash of the indexed text, used to skip unchanged chunks on re-ingestion
+        pub content_hash: String => Keyword,
+        /// Tokens in `content`, summed into the repository's metadata
+        #[serde(default)]
+        pub tokens: u64 => Long,
+        /// Vector of the content for kNN search. The mapping generated here is
+        /// replaced in `IndexSchema::docs_with_dims` to set the dimensions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub embedding: Option<Vec<f32>> => DenseVector,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn serialized_fields_match_the_mapping() {
+        let chunk = DocChunk {
+            chunk_id: "id".to_string(),
+            title: "Bulk".to_string(),
+            content: "Send many documents at once".to_string(),
+            date: Utc::now(),
+            tags: vec!["api".to_string()],
+            repo: "elasticsearch-rs".to_string(),
+            git_ref: Some("v8.5.0".to_string()),
+            commit: Some("0123abcd".to_string()),
+            version: Some("8.5.0".to_string()),
+            path: "docs/bulk.md".to_string(),
+            heading_path: vec!["Bulk".to_string()],
+            content_hash: "hash".to_string(),
+            tokens: 5,
+            embedding: Some(vec![0.0; 4]),
+        };
+        let serialized = serde_json::to_value(&chunk).unwrap();
+        let keys: BTreeSet<&String> = serialized.as_object().unwrap().keys().collect();
+        let mappings = DocChunk::mappings();
+
+        assert_eq!(keys, mappings.properties.keys().collect());
+    }
+}
@@ -0,0 +1,364 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use super::Embedder;
+
+const HITS: &str = "hits";
+const MISSES: &str = "misses";
+const BYTES: &str = "bytes";
+
+/// Bytes in front of every cached vector, the access stamp used for eviction
+const STAMP_LEN: usize = 8;
+
+/// Vectors on disk, keyed by embedder model id and a hash of the embedded text.
+/// The least recently used vectors are evicted once the cache holds more than
+/// `max_bytes` of them.
+#[derive(Debug, Clone)]
+pub struct EmbeddingCache {
+    db: sled::Db,
+    /// Key -> access stamp followed by the vector as little endian f32s
+    entries: sled::Tree,
+    /// Access stamp followed by the key -> nothing, oldest first
+    lru: sled::Tree,
+    /// Hit and miss counters and the size of `entries`
+    counters: sled::Tree,
+    max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return None;
+        }
+        Some(self.hits as f64 / lookups as f64)
+    }
+}
+
+impl EmbeddingCache {
+    /// Open the cache in `path`, created when missing. A cache can only be
+    /// open in one process at a time, so commands using it fail while `serve`
+    /// has it open unless they are pointed at another directory.
+    pub fn open(path: &Path, max_bytes: u64) -> Result<Self> {
+        let db = match sled::open(path) {
+            Ok(db) => db,
+            Err(sled::Error::Io(err)) if err.to_string().contains("could not acquire lock") => {
+                bail!(
+                    "The embedding cache {} is in use by another process, e.g. a running \
+                     `serve`. Stop it or set a different --embedding-cache.",
+                    path.display()
+                )
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to open the embedding cache {}", path.display())
+                });
+            }
+        };
+        Ok(EmbeddingCache {
+            entries: db.open_tree("entries")?,
+            lru: db.open_tree("lru")?,
+            counters: db.open_tree("counters")?,
+            db,
+            max_bytes,
+        })
+    }
+
+    /// Cached vector of `text` embedded by `model_id`, marked as recently used
+    pub fn get(&self, model_id: &str, text: &str) -> Result<Option<Vec<f32>>> {
+        let key = key(model_id, text);
+        let Some(value) = self.entries.get(&key)? else {
+            return Ok(None);
+        };
+
+        let stamp = self.stamp()?;
+        self.lru.remove(lru_key(&value[..STAMP_LEN], &key))?;
+        self.lru
+            .insert(lru_key(&stamp, &key), sled::IVec::default())?;
+        let mut touched = value.to_vec();
+        touched[..STAMP_LEN].copy_from_slice(&stamp);
+        self.entries.insert(&key, touched)?;
+
+        Ok(Some(decode(&value[STAMP_LEN..])))
+    }
+
+    pub fn insert(&self, model_id: &str, text: &str, vector: &[f32]) -> Result<()> {
+        let key = key(model_id, text);
+        let stamp = self.stamp()?;
+        let mut value = stamp.to_vec();
+        value.extend(vector.iter().flat_map(|value| value.to_le_bytes()));
+        let added = value.len() as i64;
+
+        if let Some(old) = self.entries.insert(&key, value)? {
+            self.lru.remove(lru_key(&old[..STAMP_LEN], &key))?;
+            self.add(BYTES, -(old.len() as i64))?;
+        }
+        self.lru
+            .insert(lru_key(&stamp, &key), sled::IVec::default())?;
+        self.add(BYTES, added)?;
+        self.evict()
+    }
+
+    /// Count lookups for the hit rate
+    pub fn record(&self, hits: usize, misses: usize) -> Result<()> {
+        self.add(HITS, hits as i64)?;
+        self.add(MISSES, misses as i64)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        Ok(CacheStats {
+            entries: self.entries.len(),
+            bytes: self.counter(BYTES)?,
+            max_bytes: self.max_bytes,
+            hits: self.counter(HITS)?,
+            misses: self.counter(MISSES)?,
+        })
+    }
+
+    /// Drop every vector and reset the counters
+    pub fn clear(&self) -> Result<()> {
+        self.entries.clear()?;
+        self.lru.clear()?;
+        self.counters.clear()?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Print entries, size and hit rate
+    pub fn print_stats(&self) -> Result<()> {
+        let stats = self.stats()?;
+        println!("Entries:   {}", stats.entries);
+        println!(
+            "Size:      {:.1} MiB of {:.1} MiB",
+            mib(stats.bytes),
+            mib(stats.max_bytes)
+        );
+        println!("Hits:      {}", stats.hits);
+        println!("Misses:    {}", stats.misses);
+        match stats.hit_rate() {
+            Some(rate) => println!("Hit rate:  {:.1}%", rate * 100.0),
+            None => println!("Hit rate:  -"),
+        }
+        Ok(())
+    }
+
+    /// Remove the least recently used vectors until the cache fits `max_bytes`
+    fn evict(&self) -> Result<()> {
+        while self.counter(BYTES)? > self.max_bytes {
+            let Some((oldest, _)) = self.lru.pop_min()? else {
+                break;
+            };
+            if let Some(old) = self.entries.remove(&oldest[STAMP_LEN..])? {
+                self.add(BYTES, -(old.len() as i64))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Increasing access stamp, big endian so the `lru` tree sorts by it
+    fn stamp(&self) -> Result<[u8; STAMP_LEN]> {
+        Ok(self.db.generate_id()?.to_be_bytes())
+    }
+
+    fn counter(&self, name: &str) -> Result<u64> {
+        Ok(self
+            .counters
+            .get(name)?
+            .map(|value| decode_u64(&value))
+            .unwrap_or_default())
+    }
+
+    fn add(&self, name: &str, delta: i64) -> Result<()> {
+        self.counters.update_and_fetch(name, |old| {
+            let old = old.map(decode_u64).unwrap_or_default();
+            Some(old.saturating_add_signed(delta).to_be_bytes().to_vec())
+        })?;
+        Ok(())
+    }
+}
+
+/// An embedder that only asks `inner` for the texts missing from `cache`
+#[derive(Debug)]
+pub struct CachedEmbedder {
+    inner: Arc<dyn Embedder>,
+    cache: EmbeddingCache,
+}
+
+impl CachedEmbedder {
+    pub fn new(inner: Arc<dyn Embedder>, cache: EmbeddingCache) -> Self {
+        CachedEmbedder { inner, cache }
+    }
+}
+
+#[async_trait]
+impl Embedder for CachedEmbedder {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let model_id = self.inner.model_id();
+        let mut vectors = Vec::with_capacity(texts.len());
+        let mut missing = Vec::new();
+        for (position, text) in texts.iter().enumerate() {
+            // Vectors of another length are treated as missing
+            match self.cache.get(model_id, text)? {
+                Some(vector) if vector.len() == self.inner.dimension() => vectors.push(vector),
+                _ => {
+                    missing.push(position);
+                    vectors.push(Vec::new());
+                }
+            }
+        }
+        self.cache
+            .record(texts.len() - missing.len(), missing.len())?;
+        if missing.is_empty() {
+            return Ok(vectors);
+        }
+
+        let missing_texts: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+        let embedded = self.inner.embed(&missing_texts).await?;
+        if embedded.len() != missing.len() {
+            bail!(
+                "Embedder returned {} vectors for {} texts",
+                embedded.len(),
+                missing.len()
+            );
+        }
+        for (position, vector) in missing.into_iter().zip(embedded) {
+            self.cache.insert(model_id, &texts[position], &vector)?;
+            vectors[position] = vector;
+        }
+        self.cache.db.flush()?;
+        Ok(vectors)
+    }
+}
+
+/// Model id, a separator and the SHA-256 of the text
+fn key(model_id: &str, text: &str) -> Vec<u8> {
+    let mut key = model_id.as_bytes().to_vec();
+    key.push(0);
+    key.extend_from_slice(&Sha256::digest(text.as_bytes()));
+    key
+}
+
+fn lru_key(stamp: &[u8], key: &[u8]) -> Vec<u8> {
+    [stamp, key].concat()
+}
+
+fn decode(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    bytes.try_into().map(u64::from_be_bytes).unwrap_or_default()
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::hashing::HashingEmbedder;
+
+    /// Bytes taken by a cached vector of `dims` dimensions
+    fn entry_bytes(dims: usize) -> u64 {
+        (STAMP_LEN + dims * 4) as u64
+    }
+
+    #[test]
+    fn least_recently_used_vectors_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = EmbeddingCache::open(dir.path(), 3 * entry_bytes(2)).unwrap();
+        for text in ["a", "b", "c"] {
+            cache.insert("model", text, &[1.0, 2.0]).unwrap();
+        }
+        // Makes "b" the least recently used
+        cache.get("model", "a").unwrap().unwrap();
+
+        cache.insert("model", "d", &[3.0, 4.0]).unwrap();
+
+        assert!(cache.get("model", "b").unwrap().is_none());
+        for text in ["a", "c", "d"] {
+            assert!(cache.get("model", text).unwrap().is_some(), "{}", text);
+        }
+        assert_eq!(cache.get("model", "d").unwrap(), Some(vec![3.0, 4.0]));
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.bytes, 3 * entry_bytes(2));
+    }
+
+    #[test]
+    fn replaced_vectors_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = EmbeddingCache::open(dir.path(), 1024).unwrap();
+        cache.insert("model", "a", &[1.0, 2.0]).unwrap();
+        cache.insert("model", "a", &[1.0, 2.0, 3.0]).unwrap();
+        cache.insert("other", "a", &[1.0]).unwrap();
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.bytes, entry_bytes(3) + entry_bytes(1));
+        assert_eq!(cache.get("model", "a").unwrap(), Some(vec![1.0, 2.0, 3.0]));
+    }
+
+    #[tokio::test]
+    async fn lookups_are_counted_as_hits_and_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = EmbeddingCache::open(dir.path(), 1024 * 1024).unwrap();
+        let embedder = CachedEmbedder::new(Arc::new(HashingEmbedder::new(8)), cache.clone());
+        let texts = |texts: &[&str]| {
+            texts
+                .iter()
+                .map(|text| text.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let first = embedder.embed(&texts(&["tokio", "axum"])).await.unwrap();
+        let second = embedder.embed(&texts(&["tokio", "sled"])).await.unwrap();
+
+        assert_eq!(first[0], second[0]);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+        assert_eq!(stats.hit_rate(), Some(0.25));
+        assert_eq!(stats.entries, 3);
+
+        cache.clear().unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.entries, stats.bytes, stats.hits), (0, 0, 0));
+    }
+
+    #[test]
+    fn a_cache_open_elsewhere_is_reported_as_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let _open = EmbeddingCache::open(dir.path(), 1024).unwrap();
+
+        let err = EmbeddingCache::open(dir.path(), 1024).unwrap_err();
+        assert!(
+            err.to_string().contains("in use by another process"),
+            "{:#}",
+            err
+        );
+    }
+}
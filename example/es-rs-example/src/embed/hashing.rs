@@ -0,0 +1,106 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::Embedder;
+
+/// Offline embedder using the hashing trick: every word is hashed into one of
+/// `dims` buckets and the counts are normalized. Texts sharing words end up
+/// close, which is enough for tests and machines without network access, but
+/// it knows nothing about synonyms or meaning.
+#[derive(Debug, Clone)]
+pub struct HashingEmbedder {
+    dims: usize,
+    model_id: String,
+}
+
+impl HashingEmbedder {
+    /// `dims` must be at least 1, the config rejects anything else
+    pub fn new(dims: usize) -> Self {
+        HashingEmbedder {
+            dims,
+            model_id: format!("hashing@{}", dims),
+        }
+    }
+
+    fn embed_one(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0f32; self.dims];
+        let words = text
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|word| !word.is_empty());
+        for word in words {
+            let hash = fnv1a(word.to_lowercase().as_bytes());
+            let bucket = (hash % self.dims as u64) as usize;
+            // The sign bit keeps collisions from only ever adding up
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[bucket] += sign;
+        }
+
+        let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            // Cosine similarity is undefined for the zero vector, Elasticsearch rejects it
+            vector[0] = 1.0;
+        } else {
+            vector.iter_mut().for_each(|value| *value /= norm);
+        }
+        vector
+    }
+}
+
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dims
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_one(text)).collect())
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and Rust versions unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn embed(embedder: &HashingEmbedder, texts: &[&str]) -> Vec<Vec<f32>> {
+        let texts: Vec<String> = texts.iter().map(|text| text.to_string()).collect();
+        embedder.embed(&texts).await.unwrap()
+    }
+
+    fn norm(vector: &[f32]) -> f32 {
+        vector.iter().map(|value| value * value).sum::<f32>().sqrt()
+    }
+
+    #[tokio::test]
+    async fn vectors_are_deterministic() {
+        let embedder = HashingEmbedder::new(64);
+        let first = embed(&embedder, &["Spawn a task", "Join handles"]).await;
+        let second = embed(&HashingEmbedder::new(64), &["spawn a TASK", "Join handles"]).await;
+
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[1]);
+    }
+
+    #[tokio::test]
+    async fn vectors_have_the_configured_dimension_and_unit_length() {
+        for dims in [1, 3, 384] {
+            let embedder = HashingEmbedder::new(dims);
+            for vector in embed(&embedder, &["Spawn a task", "", "..."]).await {
+                assert_eq!(vector.len(), dims);
+                assert!((norm(&vector) - 1.0).abs() < 1e-6, "{:?}", vector);
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+pub mod cache;
+mod hashing;
+mod openai;
+
+use std::{fmt, sync::Arc};
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+
+use crate::config::{CacheConfig, EmbedderKind, EmbeddingConfig};
+
+use cache::{CachedEmbedder, EmbeddingCache};
+use hashing::HashingEmbedder;
+use openai::HttpEmbedder;
+
+/// Turns text into vectors for the `embedding` field. Vectors are only
+/// comparable when they come from the same `model_id`.
+#[async_trait]
+pub trait Embedder: fmt::Debug + Send + Sync {
+    /// Model and settings the vectors depend on, e.g. `text-embedding-v3@1024`
+    fn model_id(&self) -> &str;
+
+    /// Length of every vector returned by `embed`
+    fn dimension(&self) -> usize;
+
+    /// One vector per text, in the same order
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+/// Build the embedder described by `config`
+pub fn from_config(config: &EmbeddingConfig) -> Result<Arc<dyn Embedder>> {
+    let embedder: Arc<dyn Embedder> = match &config.kind {
+        EmbedderKind::Hashing => Arc::new(HashingEmbedder::new(config.dims)),
+        EmbedderKind::Http {
+            url,
+            api_key,
+            model,
+        } => Arc::new(HttpEmbedder::new(
+            url,
+            api_key.clone(),
+            model,
+            config.dims,
+            config.batch_size,
+        )?),
+    };
+    Ok(embedder)
+}
+
+/// Put the on-disk cache described by `config` in front of `embedder`
+pub fn with_cache(embedder: Arc<dyn Embedder>, config: &CacheConfig) -> Result<Arc<dyn Embedder>> {
+    let cache = EmbeddingCache::open(&config.path, config.max_bytes)?;
+    Ok(Arc::new(CachedEmbedder::new(embedder, cache)))
+}
+
+/// Embed a single query text
+pub async fn embed_query(embedder: &dyn Embedder, text: &str) -> Result<Vec<f32>> {
+    let mut vectors = embedder.embed(&[text.to_string()]).await?;
+    if vectors.len() != 1 {
+        bail!("Embedder returned {} vectors for one text", vectors.len());
+    }
+    Ok(vectors.remove(0))
+}
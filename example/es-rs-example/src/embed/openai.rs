@@ -0,0 +1,242 @@
+use std::{fmt, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::Embedder;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Embedder for the OpenAI `/embeddings` API and services compatible with it,
+/// such as DashScope at `https://dashscope.aliyuncs.com/compatible-mode/v1`
+pub struct HttpEmbedder {
+    http: reqwest::Client,
+    endpoint: String,
+    api_key: Option<String>,
+    model: String,
+    dims: usize,
+    /// Texts per request, DashScope accepts at most 10
+    batch_size: usize,
+    model_id: String,
+}
+
+impl fmt::Debug for HttpEmbedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Leaves out the API key
+        f.debug_struct("HttpEmbedder")
+            .field("endpoint", &self.endpoint)
+            .field("model", &self.model)
+            .field("dims", &self.dims)
+            .field("batch_size", &self.batch_size)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl HttpEmbedder {
+    /// `url` is the API base, e.g. `https://api.openai.com/v1`
+    pub fn new(
+        url: &str,
+        api_key: Option<String>,
+        model: &str,
+        dims: usize,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to build the embedding HTTP client")?;
+
+        Ok(HttpEmbedder {
+            http,
+            endpoint: format!("{}/embeddings", url.trim_end_matches('/')),
+            api_key,
+            model: model.to_string(),
+            dims,
+            batch_size: batch_size.max(1),
+            model_id: format!("{}@{}", model, dims),
+        })
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let body = json!({
+            "model": self.model,
+            "input": texts,
+            "dimensions": self.dims,
+            "encoding_format": "float"
+        });
+        let mut request = self.http.post(&self.endpoint).json(&body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach {}", self.endpoint))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            bail!("Embedding request failed ({}): {}", status, text);
+        }
+
+        let mut body: EmbeddingResponse = response
+            .json()
+            .await
+            .context("Invalid embedding response")?;
+        if body.data.len() != texts.len() {
+            bail!(
+                "Asked for {} embeddings, got {}",
+                texts.len(),
+                body.data.len()
+            );
+        }
+        body.data.sort_by_key(|data| data.index);
+
+        body.data
+            .into_iter()
+            .map(|data| {
+                if data.embedding.len() != self.dims {
+                    bail!(
+                        "Model '{}' returned {} dimensions, expected {}",
+                        self.model,
+                        data.embedding.len(),
+                        self.dims
+                    );
+                }
+                Ok(data.embedding)
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Embedder for HttpEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimension(&self) -> usize {
+        self.dims
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.batch_size) {
+            vectors.extend(self.embed_batch(batch).await?);
+        }
+        Ok(vectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::pool::tests::{MockRequest, mock_server};
+
+    /// Answers with one `[text length, 1.0]` vector per input, in reverse order
+    fn reversed(request: &MockRequest) -> (u16, String) {
+        let body = request.json();
+        let mut data: Vec<Value> = body["input"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(index, text)| {
+                let length = text.as_str().unwrap().len() as f32;
+                json!({ "index": index, "embedding": [length, 1.0] })
+            })
+            .collect();
+        data.reverse();
+        (200, json!({ "data": data }).to_string())
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn inputs_are_batched_and_vectors_follow_their_index() {
+        let (url, requests) = mock_server(reversed).await;
+        let embedder = HttpEmbedder::new(&format!("{}/v1/", url), None, "small", 2, 2).unwrap();
+        let inputs = texts(&["a", "bb", "ccc", "dddd", "eeeee"]);
+
+        let vectors = embedder.embed(&inputs).await.unwrap();
+
+        let lengths: Vec<f32> = vectors.iter().map(|vector| vector[0]).collect();
+        assert_eq!(lengths, [1.0, 2.0, 3.0, 4.0, 5.0]);
+        let requests = requests.lock().unwrap();
+        let batches: Vec<Value> = requests
+            .iter()
+            .map(|request| request.json()["input"].clone())
+            .collect();
+        assert_eq!(
+            batches,
+            [json!(["a", "bb"]), json!(["ccc", "dddd"]), json!(["eeeee"])]
+        );
+        for request in requests.iter() {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/v1/embeddings");
+            assert_eq!(request.json()["model"], "small");
+            assert_eq!(request.json()["dimensions"], 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn vectors_of_the_wrong_length_are_rejected() {
+        let (url, _) = mock_server(reversed).await;
+        let embedder = HttpEmbedder::new(&url, None, "small", 3, 10).unwrap();
+
+        let err = embedder.embed(&texts(&["a"])).await.unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("returned 2 dimensions, expected 3"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_vectors_are_rejected() {
+        let (url, _) = mock_server(|_| (200, json!({ "data": [] }).to_string())).await;
+        let embedder = HttpEmbedder::new(&url, None, "small", 2, 10).unwrap();
+
+        let err = embedder.embed(&texts(&["a", "b"])).await.unwrap_err();
+        assert!(
+            err.to_string().contains("Asked for 2 embeddings, got 0"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_reported_with_the_body() {
+        let (url, _) = mock_server(|_| {
+            (
+                401,
+                json!({ "error": { "message": "Invalid API key" } }).to_string(),
+            )
+        })
+        .await;
+        let embedder = HttpEmbedder::new(&url, Some("wrong".to_string()), "small", 2, 10).unwrap();
+
+        let err = embedder.embed(&texts(&["a"])).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("401"), "{}", message);
+        assert!(message.contains("Invalid API key"), "{}", message);
+    }
+}
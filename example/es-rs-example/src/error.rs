@@ -0,0 +1,324 @@
+use std::process::ExitCode;
+
+use elasticsearch::http::{StatusCode, response::Response};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::schema::SchemaError;
+
+/// Process exit codes, one per failure class so scripts can react to them.
+/// 2 is left to clap for invalid command lines.
+pub mod exit {
+    pub const FAILURE: u8 = 1;
+    pub const CONNECTION: u8 = 3;
+    pub const NOT_FOUND: u8 = 4;
+    pub const ALREADY_EXISTS: u8 = 5;
+    pub const REJECTED: u8 = 6;
+    pub const SERVER: u8 = 7;
+    pub const INVALID_SCHEMA: u8 = 8;
+}
+
+/// One entry of an Elasticsearch error body, `root_cause` entries have the same shape
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorCause {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub index: Option<String>,
+    #[serde(default)]
+    pub root_cause: Vec<ErrorCause>,
+}
+
+impl ErrorCause {
+    pub(crate) fn new(error_type: &str, reason: String) -> Self {
+        ErrorCause {
+            error_type: error_type.to_string(),
+            reason: Some(reason),
+            index: None,
+            root_cause: Vec::new(),
+        }
+    }
+}
+
+/// A request Elasticsearch answered with an error status
+#[derive(Debug, Error)]
+pub enum EsError {
+    #[error("index '{index}' already exists")]
+    AlreadyExists { index: String },
+    #[error("{}", describe(.cause))]
+    NotFound { cause: ErrorCause },
+    #[error("{} (status {status}){}", describe(.cause), root_causes(.cause))]
+    Api { status: u16, cause: ErrorCause },
+}
+
+/// A request that is wrong whatever the cluster holds, e.g. `k = 0`, or that
+/// asks for something the index does not have, such as an unmatched version
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct InvalidRequest(pub String);
+
+impl EsError {
+    /// A missing document or alias, for APIs that answer 404 without an error body
+    pub fn not_found(reason: String) -> Self {
+        EsError::NotFound {
+            cause: ErrorCause::new("not_found", reason),
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            EsError::AlreadyExists { .. } => exit::ALREADY_EXISTS,
+            EsError::NotFound { .. } => exit::NOT_FOUND,
+            EsError::Api { status, .. } if *status >= 500 => exit::SERVER,
+            EsError::Api { .. } => exit::REJECTED,
+        }
+    }
+}
+
+fn describe(cause: &ErrorCause) -> String {
+    match &cause.reason {
+        Some(reason) => format!("{}: {}", cause.error_type, reason),
+        None => cause.error_type.clone(),
+    }
+}
+
+fn root_causes(cause: &ErrorCause) -> String {
+    cause
+        .root_cause
+        .iter()
+        .filter(|root| root.error_type != cause.error_type || root.reason != cause.reason)
+        .map(|root| format!("\n  caused by {}", describe(root)))
+        .collect()
+}
+
+/// Error bodies come as `{"error": {...}, "status": 400}`, a few APIs send
+/// `{"error": "message", "status": 404}` instead
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ErrorDetail {
+    Cause(ErrorCause),
+    Message(String),
+}
+
+/// Pass successful responses through and turn error responses into `EsError`
+pub async fn check(response: Response) -> anyhow::Result<Response> {
+    let status = response.status_code();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let text = response.text().await?;
+    Err(parse_error(status, &text).into())
+}
+
+/// The error described by the body `text` of a response with `status`
+fn parse_error(status: StatusCode, text: &str) -> EsError {
+    let cause = match serde_json::from_str::<ErrorBody>(text) {
+        Ok(ErrorBody {
+            error: ErrorDetail::Cause(cause),
+        }) => cause,
+        Ok(ErrorBody {
+            error: ErrorDetail::Message(message),
+        }) => ErrorCause::new(
+            &status.canonical_reason().unwrap_or("error").to_lowercase(),
+            message,
+        ),
+        // Not an Elasticsearch error body, e.g. a proxy in front of the cluster
+        Err(_) if text.is_empty() => ErrorCause::new("http_error", status.to_string()),
+        Err(_) => ErrorCause::new("http_error", text.to_string()),
+    };
+
+    match cause.error_type.as_str() {
+        "resource_already_exists_exception" => EsError::AlreadyExists {
+            index: cause.index.clone().unwrap_or_default(),
+        },
+        _ if status.as_u16() == 404 => EsError::NotFound { cause },
+        _ => EsError::Api {
+            status: status.as_u16(),
+            cause,
+        },
+    }
+}
+
+/// HTTP status the REST server answers `err` with: the status Elasticsearch
+/// rejected the request with, 400 for invalid schemas and requests and 500 otherwise
+pub fn http_status(err: &anyhow::Error) -> u16 {
+    err.chain()
+        .find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<EsError>() {
+                Some(match err {
+                    EsError::AlreadyExists { .. } => 409,
+                    EsError::NotFound { .. } => 404,
+                    EsError::Api { status, .. } => *status,
+                })
+            } else if cause.downcast_ref::<elasticsearch::Error>().is_some() {
+                Some(502)
+            } else if cause.downcast_ref::<SchemaError>().is_some()
+                || cause.downcast_ref::<InvalidRequest>().is_some()
+            {
+                Some(400)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(500)
+}
+
+/// Exit code for the first cause in the chain that has a dedicated one
+pub fn exit_code(err: &anyhow::Error) -> ExitCode {
+    let code = err
+        .chain()
+        .find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<EsError>() {
+                Some(err.exit_code())
+            } else if cause.downcast_ref::<elasticsearch::Error>().is_some() {
+                Some(exit::CONNECTION)
+            } else if cause.downcast_ref::<SchemaError>().is_some() {
+                Some(exit::INVALID_SCHEMA)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(exit::FAILURE);
+
+    ExitCode::from(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    fn parse(status: u16, text: &str) -> EsError {
+        parse_error(StatusCode::from_u16(status).unwrap(), text)
+    }
+
+    #[test]
+    fn object_bodies_keep_type_reason_and_root_causes() {
+        let err = parse(
+            400,
+            r#"{"error":{"type":"search_phase_execution_exception","reason":"all shards failed",
+               "root_cause":[{"type":"query_shard_exception","reason":"failed to create query"}]},
+               "status":400}"#,
+        );
+
+        let EsError::Api { status, cause } = &err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(*status, 400);
+        assert_eq!(cause.error_type, "search_phase_execution_exception");
+        assert_eq!(cause.root_cause[0].error_type, "query_shard_exception");
+        assert_eq!(
+            err.to_string(),
+            "search_phase_execution_exception: all shards failed (status 400)\n  \
+             caused by query_shard_exception: failed to create query"
+        );
+        assert_eq!(err.exit_code(), exit::REJECTED);
+    }
+
+    #[test]
+    fn string_bodies_are_named_after_the_status() {
+        let err = parse(405, r#"{"error":"Incorrect HTTP method","status":405}"#);
+
+        let EsError::Api { cause, .. } = &err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(cause.error_type, "method not allowed");
+        assert_eq!(cause.reason.as_deref(), Some("Incorrect HTTP method"));
+    }
+
+    #[test]
+    fn existing_indices_are_already_exists_errors() {
+        let err = parse(
+            400,
+            r#"{"error":{"type":"resource_already_exists_exception",
+               "reason":"index [docs/abc] already exists","index":"docs"},"status":400}"#,
+        );
+
+        assert!(matches!(&err, EsError::AlreadyExists { index } if index == "docs"));
+        assert_eq!(err.exit_code(), exit::ALREADY_EXISTS);
+    }
+
+    #[test]
+    fn not_found_with_and_without_a_body() {
+        let err = parse(
+            404,
+            r#"{"error":{"type":"index_not_found_exception","reason":"no such index [docs]"},
+               "status":404}"#,
+        );
+        assert!(
+            matches!(&err, EsError::NotFound { cause } if cause.error_type == "index_not_found_exception")
+        );
+
+        let err = parse(404, "");
+        let EsError::NotFound { cause } = &err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(cause.error_type, "http_error");
+        assert_eq!(cause.reason.as_deref(), Some("404 Not Found"));
+        assert_eq!(err.exit_code(), exit::NOT_FOUND);
+    }
+
+    #[test]
+    fn non_json_bodies_are_kept_as_the_reason() {
+        let err = parse(502, "<html><body>Bad Gateway</body></html>");
+
+        let EsError::Api { status, cause } = &err else {
+            panic!("{:?}", err);
+        };
+        assert_eq!(*status, 502);
+        assert_eq!(cause.error_type, "http_error");
+        assert_eq!(
+            cause.reason.as_deref(),
+            Some("<html><body>Bad Gateway</body></html>")
+        );
+        assert_eq!(err.exit_code(), exit::SERVER);
+    }
+
+    #[test]
+    fn wrapped_errors_map_to_their_cause() {
+        let wrap = |err: EsError| {
+            Err::<(), _>(err)
+                .context("Failed to create index 'docs'")
+                .context("Ingestion failed")
+                .unwrap_err()
+        };
+        let cases = [
+            (parse(404, ""), exit::NOT_FOUND, 404),
+            (
+                EsError::AlreadyExists {
+                    index: "docs".to_string(),
+                },
+                exit::ALREADY_EXISTS,
+                409,
+            ),
+            (parse(429, ""), exit::REJECTED, 429),
+            (parse(503, ""), exit::SERVER, 503),
+        ];
+        for (err, code, status) in cases {
+            let err = wrap(err);
+            assert_eq!(exit_code(&err), ExitCode::from(code), "{:#}", err);
+            assert_eq!(http_status(&err), status, "{:#}", err);
+        }
+
+        let schema = anyhow::Error::new(SchemaError(vec!["bad".to_string()])).context("Invalid");
+        assert_eq!(exit_code(&schema), ExitCode::from(exit::INVALID_SCHEMA));
+        assert_eq!(http_status(&schema), 400);
+
+        let invalid = anyhow::Error::new(InvalidRequest("k must be at least 1".to_string()))
+            .context("Search failed");
+        assert_eq!(http_status(&invalid), 400);
+
+        let other = anyhow::anyhow!("disk full").context("Ingestion failed");
+        assert_eq!(exit_code(&other), ExitCode::from(exit::FAILURE));
+        assert_eq!(http_status(&other), 500);
+    }
+}
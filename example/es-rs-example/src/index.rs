@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use elasticsearch::indices::{
+    IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesStatsParts,
+};
+use serde_json::Value;
+
+use crate::{
+    client::EsClient,
+    error::{self, EsError},
+    migrate,
+    retry::Operation,
+    schema::IndexSchema,
+};
+
+/// The schema in `path`, or the built-in docs schema with `embedding` sized
+/// to `dims`, the dimensions of the configured embedder
+pub fn load_schema(path: Option<&Path>, dims: u32) -> Result<IndexSchema> {
+    let schema = match path {
+        Some(path) => IndexSchema::from_file(path)?,
+        None => IndexSchema::docs_with_dims(dims),
+    };
+    schema.validate()?;
+    for warning in schema.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(schema)
+}
+
+/// Validate a schema and print the create index body it produces
+pub fn validate(path: Option<&Path>, dims: u32) -> Result<()> {
+    let schema = load_schema(path, dims)?;
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+pub async fn exists(client: &EsClient, index_name: &str) -> Result<bool> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.indices()
+                .exists(IndicesExistsParts::Index(&[index_name]))
+                .send()
+                .await
+        })
+        .await?;
+
+    Ok(response.status_code() == 200)
+}
+
+pub async fn create(
+    client: &EsClient,
+    index_name: &str,
+    schema: Option<&Path>,
+    dims: u32,
+) -> Result<()> {
+    let schema = load_schema(schema, dims)?;
+    let schema = &schema;
+
+    if exists(client, index_name).await? {
+        println!("Index '{}' already exists", index_name);
+        let diff = migrate::check(client, index_name, schema).await?;
+        // Breaking changes cannot be applied in place, only through a new version
+        if diff.is_breaking() {
+            println!("Run `index reindex {}` to apply the schema", index_name);
+        } else if !diff.is_up_to_date() {
+            println!("Run `index migrate {}` to apply the schema", index_name);
+        }
+        return Ok(());
+    }
+
+    println!("Creating index '{}'...", index_name);
+    if send_create(client, index_name, schema).await? {
+        println!("Successfully created index '{}'", index_name);
+    } else {
+        // Someone else created it between the exists check and the create request
+        println!("Index '{}' was created concurrently", index_name);
+        migrate::check(client, index_name, schema).await?;
+    }
+
+    Ok(())
+}
+
+/// Create `index_name` with `schema` unless it exists, so documents are never
+/// indexed with dynamic mappings
+pub async fn create_if_missing(
+    client: &EsClient,
+    index_name: &str,
+    schema: &IndexSchema,
+) -> Result<()> {
+    if exists(client, index_name).await? {
+        return Ok(());
+    }
+    schema.validate()?;
+
+    println!("Creating index '{}'...", index_name);
+    if send_create(client, index_name, schema).await? {
+        println!("Successfully created index '{}'", index_name);
+    }
+    Ok(())
+}
+
+/// Returns false when the index already exists
+async fn send_create(client: &EsClient, index_name: &str, schema: &IndexSchema) -> Result<bool> {
+    let response = client
+        .send(Operation::NonIdempotent, |es| async move {
+            es.indices()
+                .create(IndicesCreateParts::Index(index_name))
+                .body(schema)
+                .send()
+                .await
+        })
+        .await?;
+
+    match error::check(response).await {
+        Ok(_) => Ok(true),
+        Err(err)
+            if matches!(
+                err.downcast_ref::<EsError>(),
+                Some(EsError::AlreadyExists { .. })
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(err) => Err(err.context(format!("Failed to create index '{}'", index_name))),
+    }
+}
+
+pub async fn delete(client: &EsClient, index_name: &str) -> Result<()> {
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.indices()
+                .delete(IndicesDeleteParts::Index(&[index_name]))
+                .send()
+                .await
+        })
+        .await?;
+
+    error::check(response)
+        .await
+        .context("Failed to delete index")?;
+
+    println!("Deleted index '{}'", index_name);
+    Ok(())
+}
+
+pub async fn stats(client: &EsClient, index_name: &str) -> Result<()> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.indices()
+                .stats(IndicesStatsParts::Index(&[index_name]))
+                .send()
+                .await
+        })
+        .await?;
+
+    let response = error::check(response)
+        .await
+        .context("Failed to fetch index stats")?;
+
+    let body: Value = response.json().await?;
+    let primaries = &body["_all"]["primaries"];
+    println!("Index: {}", index_name);
+    println!("Documents: {}", primaries["docs"]["count"]);
+    println!("Deleted documents: {}", primaries["docs"]["deleted"]);
+    println!(
+        "Store size (bytes): {}",
+        primaries["store"]["size_in_bytes"]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::{path::Path, process::Command};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+
+/// A ref of a local repository resolved to the commit it points at
+#[derive(Debug, Clone)]
+pub struct Checkout {
+    /// The tag, branch or commit as it was asked for, e.g. `v1.2.0`
+    pub git_ref: String,
+    pub commit: String,
+    pub date: DateTime<Utc>,
+}
+
+/// Resolve `git_ref` in the repository at `repo_dir`. Only local objects are
+/// read, a ref that was never fetched is an error.
+pub fn resolve(repo_dir: &Path, git_ref: &str) -> Result<Checkout> {
+    let commit = git(
+        repo_dir,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", git_ref)],
+    )
+    .with_context(|| format!("Unknown ref '{}' in {}", git_ref, repo_dir.display()))?;
+    let commit = commit.trim().to_string();
+
+    let date = git(repo_dir, &["show", "-s", "--format=%cI", &commit])?;
+    let date = DateTime::parse_from_rfc3339(date.trim())
+        .with_context(|| format!("Invalid commit date '{}'", date.trim()))?
+        .with_timezone(&Utc);
+
+    Ok(Checkout {
+        git_ref: git_ref.to_string(),
+        commit,
+        date,
+    })
+}
+
+/// Paths of every file below `repo_dir` in the tree of `commit`, relative to
+/// `repo_dir`, which may be a subdirectory of the repository
+pub fn files(repo_dir: &Path, commit: &str) -> Result<Vec<String>> {
+    let output = git(repo_dir, &["ls-tree", "-r", "-z", "--name-only", commit])?;
+    Ok(output
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Content of `path`, relative to `repo_dir` like the paths of `files`, as of `commit`
+pub fn read_file(repo_dir: &Path, commit: &str, path: &str) -> Result<String> {
+    // `commit:path` is resolved from the repository root, `commit:./path` from `repo_dir`
+    git(
+        repo_dir,
+        &["cat-file", "blob", &format!("{}:./{}", commit, path)],
+    )
+    .with_context(|| format!("Failed to read {} at {}", path, commit))
+}
+
+/// URL of the `origin` remote, `None` when there is none or `repo_dir` is
+/// not a git repository
+pub fn remote_url(repo_dir: &Path) -> Option<String> {
+    let url = git(repo_dir, &["config", "--get", "remote.origin.url"]).ok()?;
+    let url = url.trim();
+    (!url.is_empty()).then(|| url.to_string())
+}
+
+fn git(repo_dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo_dir)
+        .args(args)
+        .output()
+        .context("Failed to run git, is it installed?")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).context("git printed invalid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn run(repo_dir: &Path, args: &[&str]) {
+        let identity = [
+            "-c",
+            "user.name=Docs",
+            "-c",
+            "user.email=docs@example.com",
+            "-c",
+            "commit.gpgsign=false",
+            "-c",
+            "tag.gpgsign=false",
+        ];
+        let mut all = identity.to_vec();
+        all.extend_from_slice(args);
+        git(repo_dir, &all).unwrap();
+    }
+
+    /// A repository with `README.md` and `docs/`, tagged `v1` and `v2`
+    fn tagged_repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("docs")).unwrap();
+        run(root, &["init", "-q"]);
+
+        fs::write(root.join("README.md"), "# Project\n").unwrap();
+        fs::write(root.join("docs/guide.md"), "# Guide\n\nVersion one\n").unwrap();
+        run(root, &["add", "."]);
+        run(root, &["commit", "-q", "-m", "First"]);
+        run(root, &["tag", "v1"]);
+
+        fs::write(root.join("docs/guide.md"), "# Guide\n\nVersion two\n").unwrap();
+        fs::write(root.join("docs/new.md"), "# New\n").unwrap();
+        run(root, &["add", "."]);
+        run(root, &["commit", "-q", "-m", "Second"]);
+        run(root, &["tag", "v2"]);
+
+        // The working tree differs from both tags
+        fs::write(root.join("docs/guide.md"), "# Guide\n\nUncommitted\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn refs_resolve_to_their_commits() {
+        let repo = tagged_repo();
+        let v1 = resolve(repo.path(), "v1").unwrap();
+        let v2 = resolve(repo.path(), "v2").unwrap();
+
+        assert_eq!(v1.git_ref, "v1");
+        assert_eq!(v1.commit.len(), 40);
+        assert_ne!(v1.commit, v2.commit);
+        assert_eq!(resolve(repo.path(), "HEAD").unwrap().commit, v2.commit);
+        assert!(resolve(repo.path(), "v3").is_err());
+    }
+
+    #[test]
+    fn files_and_contents_are_read_at_each_ref() {
+        let repo = tagged_repo();
+        let v1 = resolve(repo.path(), "v1").unwrap();
+        let v2 = resolve(repo.path(), "v2").unwrap();
+
+        assert_eq!(
+            files(repo.path(), &v1.commit).unwrap(),
+            ["README.md", "docs/guide.md"]
+        );
+        assert_eq!(
+            files(repo.path(), &v2.commit).unwrap(),
+            ["README.md", "docs/guide.md", "docs/new.md"]
+        );
+        assert_eq!(
+            read_file(repo.path(), &v1.commit, "docs/guide.md").unwrap(),
+            "# Guide\n\nVersion one\n"
+        );
+        assert_eq!(
+            read_file(repo.path(), &v2.commit, "docs/guide.md").unwrap(),
+            "# Guide\n\nVersion two\n"
+        );
+        assert!(read_file(repo.path(), &v1.commit, "docs/new.md").is_err());
+    }
+
+    #[test]
+    fn subdirectories_are_read_relative_to_themselves() {
+        let repo = tagged_repo();
+        let docs = repo.path().join("docs");
+        let v1 = resolve(&docs, "v1").unwrap();
+        let v2 = resolve(&docs, "v2").unwrap();
+
+        assert_eq!(files(&docs, &v1.commit).unwrap(), ["guide.md"]);
+        assert_eq!(files(&docs, &v2.commit).unwrap(), ["guide.md", "new.md"]);
+        assert_eq!(
+            read_file(&docs, &v1.commit, "guide.md").unwrap(),
+            "# Guide\n\nVersion one\n"
+        );
+        assert_eq!(read_file(&docs, &v2.commit, "new.md").unwrap(), "# New\n");
+        assert_eq!(remote_url(&docs), None);
+    }
+}
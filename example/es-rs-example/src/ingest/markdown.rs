@@ -0,0 +1,318 @@
+/// Limits for a single chunk, both counted in characters
+#[derive(Debug, Clone, Copy)]
+pub struct SplitOptions {
+    pub max_chars: usize,
+    /// Characters repeated from the end of the previous chunk when a section
+    /// has to be cut into several chunks
+    pub overlap: usize,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        SplitOptions {
+            max_chars: 2000,
+            overlap: 200,
+        }
+    }
+}
+
+/// A piece of a Markdown document together with the headings it sits under
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    /// Headings from H1 down to the one the content belongs to
+    pub heading_path: Vec<String>,
+    pub content: String,
+}
+
+/// Split `markdown` on H1-H6 headings, then cut sections longer than
+/// `max_chars` into overlapping chunks. Heading lines are not repeated in the
+/// content, they are kept in `heading_path`. Lines inside fenced code blocks
+/// are never taken for headings.
+pub fn split(markdown: &str, options: SplitOptions) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut body = String::new();
+    // Character and length of the open fence, only a bare fence of the same
+    // character at least as long closes it
+    let mut fence: Option<(char, usize)> = None;
+
+    for line in markdown.lines() {
+        if let Some((marker, length)) = fence {
+            let closes = parse_fence(line)
+                .is_some_and(|(c, n, info)| c == marker && n >= length && info.is_empty());
+            if closes {
+                fence = None;
+            }
+        } else if let Some((marker, length, _)) = parse_fence(line) {
+            fence = Some((marker, length));
+        } else if let Some((level, text)) = parse_heading(line) {
+            flush(&headings, &mut body, options, &mut sections);
+            headings.retain(|(parent, _)| *parent < level);
+            headings.push((level, text));
+            continue;
+        }
+
+        body.push_str(line);
+        body.push('\n');
+    }
+    flush(&headings, &mut body, options, &mut sections);
+
+    sections
+}
+
+/// ```` ```rust ```` -> `('`', 3, "rust")`. Up to three leading spaces are
+/// allowed, backtick fences cannot have backticks in their info string.
+fn parse_fence(line: &str) -> Option<(char, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = &line[indent..];
+    let marker = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let length = line.len() - line.trim_start_matches(marker).len();
+    if length < 3 {
+        return None;
+    }
+
+    let info = line[length..].trim();
+    if marker == '`' && info.contains('`') {
+        return None;
+    }
+    Some((marker, length, info))
+}
+
+/// `## Title ##` -> `(2, "Title")`. Up to three leading spaces are allowed.
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = &line[indent..];
+    let level = line.len() - line.trim_start_matches('#').len();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        return None;
+    }
+    let text = rest.trim().trim_end_matches('#').trim_end();
+    Some((level, text.to_string()))
+}
+
+fn flush(
+    headings: &[(usize, String)],
+    body: &mut String,
+    options: SplitOptions,
+    sections: &mut Vec<Section>,
+) {
+    let content = std::mem::take(body);
+    let content = content.trim();
+    if content.is_empty() {
+        return;
+    }
+
+    let heading_path: Vec<String> = headings.iter().map(|(_, text)| text.clone()).collect();
+    for chunk in chunk_text(content, options) {
+        sections.push(Section {
+            heading_path: heading_path.clone(),
+            content: chunk,
+        });
+    }
+}
+
+/// Pack paragraphs into chunks of at most `max_chars`. Paragraphs that are
+/// longer on their own are cut at the last whitespace before the limit, short
+/// enough to leave room for the overlap in front of each piece.
+fn chunk_text(text: &str, options: SplitOptions) -> Vec<String> {
+    let max_chars = options.max_chars.max(1);
+    let overlap = options.overlap.min(max_chars / 2);
+    if text.chars().count() <= max_chars {
+        return vec![text.to_string()];
+    }
+    // The overlap and the space joining it to the piece
+    let piece_chars = if overlap == 0 {
+        max_chars
+    } else {
+        (max_chars - overlap - 1).max(1)
+    };
+
+    // Each piece remembers how it joins the text before it: a blank line
+    // between paragraphs, a space where a long paragraph was cut
+    let mut pieces = Vec::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let mut rest = paragraph;
+        let mut separator = "\n\n";
+        let limit = if rest.chars().count() > max_chars {
+            piece_chars
+        } else {
+            max_chars
+        };
+        while rest.chars().count() > limit {
+            let cut = cut_point(rest, limit);
+            pieces.push((separator, rest[..cut].trim_end()));
+            rest = rest[cut..].trim_start();
+            separator = " ";
+        }
+        if !rest.is_empty() {
+            pieces.push((separator, rest));
+        }
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for (separator, piece) in pieces {
+        let joined = current.chars().count() + separator.len() + piece.chars().count();
+        if !current.is_empty() && joined > max_chars {
+            let tail = overlap_tail(&current, overlap).to_string();
+            chunks.push(std::mem::take(&mut current));
+            // Only carry the overlap over if the next piece still fits next to it
+            if tail.chars().count() + separator.len() + piece.chars().count() <= max_chars {
+                current = tail;
+            }
+        }
+        if !current.is_empty() {
+            current.push_str(separator);
+        }
+        current.push_str(piece);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Byte offset to cut `text` at so the first part has at most `max_chars`
+/// characters, preferring the last whitespace
+fn cut_point(text: &str, max_chars: usize) -> usize {
+    let limit = text
+        .char_indices()
+        .nth(max_chars)
+        .map_or(text.len(), |(offset, _)| offset);
+
+    match text[..limit].rfind(char::is_whitespace) {
+        Some(offset) if offset > limit / 2 => offset,
+        _ => limit,
+    }
+}
+
+/// The last `overlap` characters of `text`, starting at a word boundary
+fn overlap_tail(text: &str, overlap: usize) -> &str {
+    if overlap == 0 {
+        return "";
+    }
+    let count = text.chars().count();
+    if count <= overlap {
+        return text;
+    }
+
+    let start = text
+        .char_indices()
+        .nth(count - overlap)
+        .map_or(text.len(), |(offset, _)| offset);
+    let tail = &text[start..];
+    match tail.find(char::is_whitespace) {
+        Some(offset) => tail[offset..].trim_start(),
+        None => tail,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(count: usize) -> String {
+        (0..count)
+            .map(|i| format!("w{:03}", i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn long_paragraph_chunks_overlap() {
+        let options = SplitOptions {
+            max_chars: 50,
+            overlap: 10,
+        };
+        // 30 words of 4 characters, 149 characters
+        let text = words(30);
+        let chunks = chunk_text(&text, options);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 50, "{:?} is too long", chunk);
+        }
+        for pair in chunks.windows(2) {
+            let tail = overlap_tail(&pair[0], 10);
+            assert!(!tail.is_empty());
+            assert!(
+                pair[1].starts_with(tail),
+                "{:?} does not repeat the end of {:?}",
+                pair[1],
+                pair[0]
+            );
+        }
+        assert!(chunks.last().unwrap().ends_with("w029"));
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        let chunks = chunk_text("short", SplitOptions::default());
+        assert_eq!(chunks, vec!["short".to_string()]);
+    }
+
+    #[test]
+    fn headings_inside_code_blocks_are_content() {
+        let markdown = "# Guide\n\n## Setup\n\n```sh\n# not a heading\n```\n";
+        let sections = split(markdown, SplitOptions::default());
+
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].heading_path, vec!["Guide", "Setup"]);
+        assert!(sections[0].content.contains("# not a heading"));
+    }
+
+    #[test]
+    fn fences_close_only_on_a_bare_fence_as_long_as_the_opening_one() {
+        let markdown = "# Guide\n\n\
+            ````md\n```rust\n# not a heading\n```\n# still not a heading\n````\n\n\
+            ~~~\n```\n# tilde fence\n~~~~\n\n\
+            ```sh\n```rust\n# info strings do not close\n```\n\n\
+            ## Next\n\nAfter the code\n";
+        let sections = split(markdown, SplitOptions::default());
+
+        assert_eq!(sections.len(), 2, "{:?}", sections);
+        assert_eq!(sections[0].heading_path, ["Guide"]);
+        for line in [
+            "# not a heading",
+            "# still not a heading",
+            "# tilde fence",
+            "# info strings do not close",
+        ] {
+            assert!(sections[0].content.contains(line), "{} is missing", line);
+        }
+        assert_eq!(sections[1].heading_path, ["Guide", "Next"]);
+        assert_eq!(sections[1].content, "After the code");
+    }
+
+    #[test]
+    fn heading_path_resets_on_a_higher_heading() {
+        let markdown = "# One\n\n## Two\n\n### Three\n\nDeep\n\n# Four\n\nTop\n\n## Five\n\nSide\n";
+        let sections = split(markdown, SplitOptions::default());
+
+        let paths: Vec<&[String]> = sections
+            .iter()
+            .map(|section| section.heading_path.as_slice())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                vec!["One", "Two", "Three"],
+                vec!["Four"],
+                vec!["Four", "Five"]
+            ]
+        );
+        assert_eq!(sections[1].content, "Top");
+    }
+}
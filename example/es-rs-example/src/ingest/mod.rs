@@ -0,0 +1,775 @@
+pub mod git;
+pub mod markdown;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use elasticsearch::{
+    ClearScrollParts, DeleteByQueryParts, ScrollParts, SearchParts, indices::IndicesRefreshParts,
+};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{
+    bulk::{self, BulkItem, BulkOptions},
+    client::EsClient,
+    document::DocChunk,
+    embed::Embedder,
+    error, index,
+    metadata::{MetadataStore, NewRepository, RepoStatus},
+    retry::Operation,
+    schema::{DEFAULT_EMBEDDING_DIMS, IndexSchema},
+    tokenizer::Tokenizer,
+    version,
+};
+
+use git::Checkout;
+use markdown::SplitOptions;
+
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdx"];
+
+/// How long Elasticsearch keeps the scroll context between pages
+const SCROLL_KEEP_ALIVE: &str = "1m";
+const SCROLL_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct IngestOptions {
+    pub root: PathBuf,
+    pub index: String,
+    /// Name the chunks are stored under, chunks of other repositories are never touched
+    pub repo: String,
+    /// Library version stored on every chunk
+    pub version: Option<String>,
+    /// Tags attached to every chunk
+    pub tags: Vec<String>,
+    pub split: SplitOptions,
+    pub bulk: BulkOptions,
+    /// Computes the `embedding` of new and changed chunks, none are stored when `None`
+    pub embedder: Option<Arc<dyn Embedder>>,
+    /// Counts the tokens reported in `IngestStats`
+    pub tokenizer: Arc<dyn Tokenizer>,
+}
+
+/// Size of what was ingested, stored as `snippets` and `tokens` in the metadata store
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IngestStats {
+    pub snippets: usize,
+    pub tokens: usize,
+}
+
+/// Markdown files below `root`, skipping hidden files and directories
+pub fn markdown_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let walker = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !is_hidden(entry));
+
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_file() && is_markdown(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Default repository name for `dir`, the name of the directory itself
+pub fn repo_name(dir: &Path) -> Result<String> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", dir.display()))?;
+    dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .with_context(|| {
+            format!(
+                "Cannot derive a repository name from {}, pass --repo",
+                dir.display()
+            )
+        })
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| MARKDOWN_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+/// Split one Markdown file of the working tree into chunks ready to be indexed
+pub fn chunk_file(root: &Path, path: &Path, options: &IngestOptions) -> Result<Vec<DocChunk>> {
+    let markdown = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let date: DateTime<Utc> = std::fs::metadata(path)?.modified()?.into();
+
+    // Forward slashes keep paths stable between platforms
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let relative = relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    Ok(chunk_markdown(&markdown, &relative, date, None, options))
+}
+
+/// Split `markdown`, stored at `path` relative to the repository root. Chunks
+/// taken from a git ref record the ref and commit they were read at.
+pub fn chunk_markdown(
+    markdown: &str,
+    path: &str,
+    date: DateTime<Utc>,
+    checkout: Option<&Checkout>,
+    options: &IngestOptions,
+) -> Vec<DocChunk> {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let git_ref = checkout.map(|checkout| checkout.git_ref.clone());
+    let commit = checkout.map(|checkout| checkout.commit.clone());
+    let model_id = options
+        .embedder
+        .as_ref()
+        .map(|embedder| embedder.model_id());
+
+    // Counts sections that share a heading path, e.g. a long section cut in
+    // several chunks, so each of them gets its own id
+    let mut seen: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut chunks = Vec::new();
+    for section in markdown::split(markdown, options.split) {
+        let ordinal = seen.entry(section.heading_path.clone()).or_default();
+        let id = chunk_id(
+            &options.repo,
+            git_ref.as_deref(),
+            options.version.as_deref(),
+            path,
+            &section.heading_path,
+            *ordinal,
+        );
+        *ordinal += 1;
+
+        let title = section
+            .heading_path
+            .last()
+            .cloned()
+            .unwrap_or_else(|| stem.clone());
+        let content_hash = content_hash(
+            &section.heading_path,
+            &section.content,
+            &options.tags,
+            model_id,
+        );
+        let tokens = options.tokenizer.count(&section.content) as u64;
+        chunks.push(DocChunk {
+            chunk_id: id,
+            title,
+            content: section.content,
+            date,
+            tags: options.tags.clone(),
+            repo: options.repo.clone(),
+            git_ref: git_ref.clone(),
+            commit: commit.clone(),
+            version: options.version.clone(),
+            path: path.to_string(),
+            heading_path: section.heading_path,
+            content_hash,
+            tokens,
+            embedding: None,
+        });
+    }
+
+    chunks
+}
+
+/// Ingest `options.root`, as it is at `git_ref` when given, and keep the
+/// repository's row in `store` up to date: in progress while running, then
+/// completed with the counts of all its versions, or failed. Returns the
+/// counts stored in `store`, those of the ingested version without one.
+pub async fn ingest_repo(
+    client: &EsClient,
+    store: Option<&MetadataStore>,
+    options: &IngestOptions,
+    git_ref: Option<&str>,
+) -> Result<IngestStats> {
+    let Some(store) = store else {
+        return ingest(client, options, git_ref).await;
+    };
+
+    let repo_url = git::remote_url(&options.root);
+    let repository = NewRepository {
+        name: options.repo.clone(),
+        description: None,
+        repo: repo_url
+            .as_deref()
+            .and_then(repo_slug)
+            .unwrap_or_else(|| options.repo.clone()),
+        repo_url: repo_url.unwrap_or_else(|| options.root.display().to_string()),
+    };
+    store.upsert(&repository).await?;
+    store
+        .set_status(&options.repo, RepoStatus::InProgress)
+        .await?;
+
+    // Counted over every version of the repository, not just the one ingested
+    let result = match ingest(client, options, git_ref).await {
+        Ok(_) => repo_stats(client, &options.index, &options.repo).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(stats) => {
+            store
+                .set_counts(&options.repo, stats.tokens as i64, stats.snippets as i64)
+                .await?;
+            Ok(stats)
+        }
+        Err(err) => {
+            if let Err(status_err) = store.set_status(&options.repo, RepoStatus::Failed).await {
+                eprintln!(
+                    "Failed to mark '{}' as failed: {:#}",
+                    options.repo, status_err
+                );
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn ingest(
+    client: &EsClient,
+    options: &IngestOptions,
+    git_ref: Option<&str>,
+) -> Result<IngestStats> {
+    match git_ref {
+        Some(git_ref) => ingest_git(client, options, git_ref).await,
+        None => ingest_dir(client, options).await,
+    }
+}
+
+/// `org/project` from a remote URL such as `https://github.com/org/project.git`
+/// or `git@github.com:org/project.git`
+fn repo_slug(url: &str) -> Option<String> {
+    let path = url.trim_end_matches('/').trim_end_matches(".git");
+    let mut parts = path.rsplit(['/', ':']);
+    let project = parts.next().filter(|part| !part.is_empty())?;
+    let org = parts.next().filter(|part| !part.is_empty())?;
+    Some(format!("{}/{}", org, project))
+}
+
+/// Walk `options.root`, split every Markdown file on its headings and index the chunks
+pub async fn ingest_dir(client: &EsClient, options: &IngestOptions) -> Result<IngestStats> {
+    let root = options.root.as_path();
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+
+    let files = markdown_files(root)?;
+    println!("Found {} Markdown files in {}", files.len(), root.display());
+
+    let mut chunks = Vec::new();
+    for path in &files {
+        chunks.extend(chunk_file(root, path, options)?);
+    }
+    println!("Split into {} chunks", chunks.len());
+
+    sync_chunks(client, options, None, chunks).await
+}
+
+/// Index the Markdown files of the git repository at `options.root` as they
+/// are at `git_ref`, without touching the working tree or the network.
+/// Every ref is stored separately, so several versions can sit side by side.
+pub async fn ingest_git(
+    client: &EsClient,
+    options: &IngestOptions,
+    git_ref: &str,
+) -> Result<IngestStats> {
+    let root = options.root.as_path();
+    let checkout = git::resolve(root, git_ref)?;
+    println!(
+        "Reading '{}' at {} ({})",
+        options.repo, checkout.git_ref, checkout.commit
+    );
+    let options = &IngestOptions {
+        version: options
+            .version
+            .clone()
+            .or_else(|| version::from_ref(git_ref)),
+        ..options.clone()
+    };
+
+    let files: Vec<String> = git::files(root, &checkout.commit)?
+        .into_iter()
+        .filter(|path| {
+            is_markdown(Path::new(path)) && !path.split('/').any(|part| part.starts_with('.'))
+        })
+        .collect();
+    println!("Found {} Markdown files", files.len());
+
+    let mut chunks = Vec::new();
+    for path in &files {
+        let markdown = git::read_file(root, &checkout.commit, path)?;
+        chunks.extend(chunk_markdown(
+            &markdown,
+            path,
+            checkout.date,
+            Some(&checkout),
+            options,
+        ));
+    }
+    println!("Split into {} chunks", chunks.len());
+
+    sync_chunks(client, options, Some(&checkout.git_ref), chunks).await
+}
+
+/// Delete every chunk of `repo`, across all refs and versions, and return how
+/// many were deleted
+pub async fn delete_repo(client: &EsClient, index_name: &str, repo: &str) -> Result<u64> {
+    let body = json!({ "query": { "term": { "repo": repo } } });
+    let body = &body;
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.delete_by_query(DeleteByQueryParts::Index(&[index_name]))
+                .refresh(true)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let body: Value = error::check(response)
+        .await
+        .with_context(|| format!("Failed to delete '{}'", repo))?
+        .json()
+        .await?;
+    Ok(body["deleted"].as_u64().unwrap_or_default())
+}
+
+/// Chunks and tokens of every version of `repo` indexed in `index_name`
+pub async fn repo_stats(client: &EsClient, index_name: &str, repo: &str) -> Result<IngestStats> {
+    // Makes the chunks just written visible to the aggregation
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.indices()
+                .refresh(IndicesRefreshParts::Index(&[index_name]))
+                .send()
+                .await
+        })
+        .await?;
+    error::check(response)
+        .await
+        .with_context(|| format!("Failed to refresh '{}'", index_name))?;
+
+    let body = json!({
+        "query": { "term": { "repo": repo } },
+        "track_total_hits": true,
+        "aggs": { "tokens": { "sum": { "field": "tokens" } } }
+    });
+    let body = &body;
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.search(SearchParts::Index(&[index_name]))
+                .size(0)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let body: Value = error::check(response)
+        .await
+        .with_context(|| format!("Failed to count the chunks of '{}'", repo))?
+        .json()
+        .await?;
+
+    Ok(IngestStats {
+        snippets: body["hits"]["total"]["value"].as_u64().unwrap_or_default() as usize,
+        tokens: body["aggregations"]["tokens"]["value"]
+            .as_f64()
+            .unwrap_or_default() as usize,
+    })
+}
+
+/// Stable id of a chunk, so re-running ingestion overwrites it instead of adding a copy
+fn chunk_id(
+    repo: &str,
+    git_ref: Option<&str>,
+    version: Option<&str>,
+    path: &str,
+    heading_path: &[String],
+    ordinal: usize,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(repo.as_bytes());
+    hasher.update([0]);
+    // A presence marker keeps a ref `main` apart from a version `main`
+    for part in [git_ref, version] {
+        match part {
+            Some(part) => {
+                hasher.update([1]);
+                hasher.update(part.as_bytes());
+                hasher.update([0]);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher.update(path.as_bytes());
+    hasher.update([0]);
+    for heading in heading_path {
+        hasher.update(heading.as_bytes());
+        hasher.update([0x1f]);
+    }
+    hasher.update(ordinal.to_le_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash of what a chunk is indexed and embedded from. The date and the
+/// commit are left out so a new commit or touching a file only reindexes
+/// the sections that changed. Tags are part of it so new tags are applied,
+/// and so is the embedding model, so switching models embeds every chunk again.
+fn content_hash(
+    heading_path: &[String],
+    content: &str,
+    tags: &[String],
+    model_id: Option<&str>,
+) -> String {
+    let mut hasher = Sha256::new();
+    for heading in heading_path {
+        hasher.update(heading.as_bytes());
+        hasher.update([0x1f]);
+    }
+    hasher.update([0]);
+    for part in [content]
+        .into_iter()
+        .chain(tags.iter().map(String::as_str))
+        .chain(model_id)
+    {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Index new and changed chunks of `options.repo` at `git_ref` and
+/// `options.version` and delete the stored chunks whose sections no longer exist
+async fn sync_chunks(
+    client: &EsClient,
+    options: &IngestOptions,
+    git_ref: Option<&str>,
+    chunks: Vec<DocChunk>,
+) -> Result<IngestStats> {
+    let stats = IngestStats {
+        snippets: chunks.len(),
+        tokens: chunks.iter().map(|chunk| chunk.tokens as usize).sum(),
+    };
+    // Without the docs mappings `repo` and `version` would be text fields
+    // that searches cannot aggregate on
+    let dims = options
+        .embedder
+        .as_ref()
+        .map_or(DEFAULT_EMBEDDING_DIMS, |embedder| {
+            embedder.dimension() as u32
+        });
+    index::create_if_missing(client, &options.index, &IndexSchema::docs_with_dims(dims)).await?;
+
+    let scope = scope_query(&options.repo, git_ref, options.version.as_deref());
+    let mut stored = stored_hashes(client, &options.index, &options.repo, scope).await?;
+
+    let total = chunks.len();
+    let mut changed_chunks: Vec<DocChunk> = chunks
+        .into_iter()
+        .filter(|chunk| {
+            stored
+                .remove(&chunk.chunk_id)
+                .is_none_or(|hash| hash != chunk.content_hash)
+        })
+        .collect();
+    if let Some(embedder) = &options.embedder {
+        embed_chunks(embedder.as_ref(), &mut changed_chunks).await?;
+    }
+
+    let changed = changed_chunks.len();
+    let mut items = Vec::new();
+    for chunk in changed_chunks {
+        items.push(BulkItem::Index {
+            id: Some(chunk.chunk_id.clone()),
+            source: serde_json::to_value(chunk)?,
+        });
+    }
+    let removed = stored.len();
+    items.extend(stored.into_keys().map(|id| BulkItem::Delete { id }));
+
+    if items.is_empty() {
+        println!("All {} chunks in '{}' are up to date", total, options.index);
+        return Ok(stats);
+    }
+    let report = bulk::send_all(client, &options.index, items, options.bulk).await?;
+
+    println!(
+        "{} chunks unchanged, {} indexed, {} deleted in '{}'",
+        total - changed,
+        changed,
+        removed,
+        options.index
+    );
+    if !report.failures.is_empty() {
+        report.print_failures();
+        bail!("{} bulk operations failed", report.failures.len());
+    }
+    Ok(stats)
+}
+
+/// Set the embedding of every chunk, computed from its heading path and content
+async fn embed_chunks(embedder: &dyn Embedder, chunks: &mut [DocChunk]) -> Result<()> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "Embedding {} chunks with {}",
+        chunks.len(),
+        embedder.model_id()
+    );
+
+    let texts: Vec<String> = chunks.iter().map(embedding_text).collect();
+    let vectors = embedder.embed(&texts).await?;
+    if vectors.len() != chunks.len() {
+        bail!(
+            "Embedder returned {} vectors for {} chunks",
+            vectors.len(),
+            chunks.len()
+        );
+    }
+    for (chunk, vector) in chunks.iter_mut().zip(vectors) {
+        if vector.len() != embedder.dimension() {
+            bail!(
+                "{} returned {} dimensions, expected {}",
+                embedder.model_id(),
+                vector.len(),
+                embedder.dimension()
+            );
+        }
+        chunk.embedding = Some(vector);
+    }
+    Ok(())
+}
+
+/// Text a chunk is embedded from, the headings give short sections their context
+fn embedding_text(chunk: &DocChunk) -> String {
+    if chunk.heading_path.is_empty() {
+        return chunk.content.clone();
+    }
+    format!("{}\n\n{}", chunk.heading_path.join(" > "), chunk.content)
+}
+
+/// Ids and content hashes of every chunk of `repo` matching `scope`
+async fn stored_hashes(
+    client: &EsClient,
+    index_name: &str,
+    repo: &str,
+    scope: Value,
+) -> Result<HashMap<String, String>> {
+    let mut hashes = HashMap::new();
+    if !index::exists(client, index_name).await? {
+        return Ok(hashes);
+    }
+
+    let body = json!({
+        "query": scope,
+        "_source": ["content_hash"],
+        "size": SCROLL_PAGE_SIZE
+    });
+    let body = &body;
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.search(SearchParts::Index(&[index_name]))
+                .scroll(SCROLL_KEEP_ALIVE)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let mut page: Value = error::check(response)
+        .await
+        .with_context(|| format!("Failed to list chunks of '{}'", repo))?
+        .json()
+        .await?;
+
+    loop {
+        let hits = page["hits"]["hits"].as_array().cloned().unwrap_or_default();
+        let scroll_id = page["_scroll_id"].as_str().map(str::to_string);
+        for hit in &hits {
+            if let (Some(id), Some(hash)) =
+                (hit["_id"].as_str(), hit["_source"]["content_hash"].as_str())
+            {
+                hashes.insert(id.to_string(), hash.to_string());
+            }
+        }
+
+        let Some(scroll_id) = scroll_id else {
+            break;
+        };
+        if hits.is_empty() {
+            clear_scroll(client, &scroll_id).await?;
+            break;
+        }
+
+        let body = json!({ "scroll": SCROLL_KEEP_ALIVE, "scroll_id": scroll_id });
+        let body = &body;
+        let response = client
+            .send(Operation::Read, |es| async move {
+                es.scroll(ScrollParts::None).body(body).send().await
+            })
+            .await?;
+        page = error::check(response)
+            .await
+            .with_context(|| format!("Failed to list chunks of '{}'", repo))?
+            .json()
+            .await?;
+    }
+
+    Ok(hashes)
+}
+
+/// Chunks of `repo` read at `git_ref` for `version`. A `None` matches chunks
+/// without the field, so a working tree never replaces a ref and vice versa.
+fn scope_query(repo: &str, git_ref: Option<&str>, version: Option<&str>) -> Value {
+    let mut filter = vec![json!({ "term": { "repo": repo } })];
+    let mut must_not = Vec::new();
+    for (field, value) in [("git_ref", git_ref), ("version", version)] {
+        match value {
+            Some(value) => filter.push(json!({ "term": { field: value } })),
+            None => must_not.push(json!({ "exists": { "field": field } })),
+        }
+    }
+    json!({ "bool": { "filter": filter, "must_not": must_not } })
+}
+
+async fn clear_scroll(client: &EsClient, scroll_id: &str) -> Result<()> {
+    let body = json!({ "scroll_id": [scroll_id] });
+    let body = &body;
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.clear_scroll(ClearScrollParts::None)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    error::check(response)
+        .await
+        .context("Failed to clear scroll")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headings(headings: &[&str]) -> Vec<String> {
+        headings.iter().map(|heading| heading.to_string()).collect()
+    }
+
+    #[test]
+    fn chunk_ids_are_stable_and_scoped() {
+        let id = |git_ref, version, ordinal| {
+            chunk_id(
+                "tokio",
+                git_ref,
+                version,
+                "guide.md",
+                &headings(&["Guide", "Tasks"]),
+                ordinal,
+            )
+        };
+
+        assert_eq!(
+            id(Some("v1"), Some("1.0.0"), 0),
+            id(Some("v1"), Some("1.0.0"), 0)
+        );
+        let distinct = [
+            id(Some("v1"), Some("1.0.0"), 0),
+            id(Some("v1"), Some("1.0.0"), 1),
+            id(Some("v2"), Some("1.0.0"), 0),
+            id(Some("v1"), Some("2.0.0"), 0),
+            id(None, None, 0),
+        ];
+        for (i, a) in distinct.iter().enumerate() {
+            for b in &distinct[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_ne!(id(Some("x"), None, 0), id(None, Some("x"), 0));
+    }
+
+    #[test]
+    fn chunk_ids_do_not_mix_up_heading_boundaries() {
+        let id = |heading_path: &[&str]| {
+            chunk_id("tokio", None, None, "a.md", &headings(heading_path), 0)
+        };
+
+        assert_ne!(id(&["ab", "c"]), id(&["a", "bc"]));
+        assert_ne!(id(&["a"]), id(&["a", ""]));
+    }
+
+    #[test]
+    fn content_hash_only_changes_with_what_is_indexed() {
+        let heading_path = headings(&["Guide", "Tasks"]);
+        let tags = headings(&["async"]);
+        let hash = content_hash(&heading_path, "Spawn a task", &tags, Some("hashing@8"));
+
+        assert_eq!(
+            hash,
+            content_hash(&heading_path, "Spawn a task", &tags, Some("hashing@8"))
+        );
+        let changed = [
+            content_hash(&heading_path, "Spawn two tasks", &tags, Some("hashing@8")),
+            content_hash(
+                &headings(&["Guide"]),
+                "Spawn a task",
+                &tags,
+                Some("hashing@8"),
+            ),
+            content_hash(&heading_path, "Spawn a task", &[], Some("hashing@8")),
+            content_hash(&heading_path, "Spawn a task", &tags, Some("hashing@16")),
+            content_hash(&heading_path, "Spawn a task", &tags, None),
+        ];
+        for other in changed {
+            assert_ne!(hash, other);
+        }
+    }
+
+    #[test]
+    fn scope_query_matches_the_ref_and_version_or_their_absence() {
+        assert_eq!(
+            scope_query("tokio", Some("v1.0.0"), Some("1.0.0")),
+            json!({ "bool": {
+                "filter": [
+                    { "term": { "repo": "tokio" } },
+                    { "term": { "git_ref": "v1.0.0" } },
+                    { "term": { "version": "1.0.0" } }
+                ],
+                "must_not": []
+            } })
+        );
+        assert_eq!(
+            scope_query("tokio", None, None),
+            json!({ "bool": {
+                "filter": [{ "term": { "repo": "tokio" } }],
+                "must_not": [
+                    { "exists": { "field": "git_ref" } },
+                    { "exists": { "field": "version" } }
+                ]
+            } })
+        );
+    }
+}
@@ -1,56 +1,308 @@
-use anyhow::Result;
-use elasticsearch::{Elasticsearch, indices::{IndicesCreateParts, IndicesExistsParts}, http::transport::Transport,};
-use serde_json::json;
+mod bulk;
+mod cli;
+mod client;
+mod config;
+mod context;
+mod doc;
+mod document;
+mod embed;
+mod error;
+mod index;
+mod ingest;
+mod mcp;
+mod metadata;
+mod migrate;
+mod pool;
+mod reindex;
+mod retry;
+mod schema;
+mod search;
+mod server;
+mod tokenizer;
+mod version;
+
+use std::process::ExitCode;
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+
+use bulk::BulkOptions;
+use cli::{CacheCommand, Cli, Command, DocCommand, FilterArgs, IndexCommand, RepoCommand};
+use client::EsClient;
+use config::Config;
+use context::ContextQuery;
+use embed::{Embedder, cache::EmbeddingCache};
+use error::EsError;
+use ingest::{IngestOptions, markdown::SplitOptions};
+use metadata::MetadataStore;
+use search::{Filters, HybridQuery, KnnQuery, RrfParams, SearchQuery};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    println!("Connecting to Elasticsearch...");
-    
-    let transport = Transport::single_node("http://localhost:9200")?;
-    let client = Elasticsearch::new(transport);
-    
-    // Define the index name
-    let index_name = "my_index";
-    
-    // Check if the index already exists
-    let exists_response = client
-        .indices()
-        .exists(IndicesExistsParts::Index(&[index_name]))
-        .send()
-        .await?;
-    
-    if exists_response.status_code() == 200 {
-        println!("Index '{}' already exists", index_name);
-    } else {
-        // Create the index with mappings
-        println!("Creating index '{}'...", index_name);
-        
-        let response = client
-            .indices()
-            .create(IndicesCreateParts::Index(index_name))
-            .body(json!({
-                "settings": {
-                    "number_of_shards": 1,
-                    "number_of_replicas": 0
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+            error::exit_code(&err)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let config = Config::load(&cli.connection)?;
+    let client = EsClient::connect(&config).await?;
+    let embedder = config
+        .embedding
+        .as_ref()
+        .map(embed::from_config)
+        .transpose()?;
+
+    let dims = config.embedding_dims();
+
+    match cli.command {
+        Command::Index(command) => match command {
+            IndexCommand::Create { index, schema } => {
+                index::create(&client, &index, schema.as_deref(), dims).await?
+            }
+            IndexCommand::Diff { index, schema } => {
+                let schema = index::load_schema(schema.as_deref(), dims)?;
+                migrate::check(&client, &index, &schema).await?;
+            }
+            IndexCommand::Migrate {
+                index,
+                schema,
+                dry_run,
+            } => {
+                let schema = index::load_schema(schema.as_deref(), dims)?;
+                migrate::migrate(&client, &index, &schema, dry_run).await?
+            }
+            IndexCommand::Init { alias, schema } => {
+                let schema = index::load_schema(schema.as_deref(), dims)?;
+                reindex::init(&client, &alias, &schema).await?
+            }
+            IndexCommand::Reindex {
+                alias,
+                schema,
+                delete_old,
+            } => {
+                let schema = index::load_schema(schema.as_deref(), dims)?;
+                reindex::reindex(&client, &alias, &schema, delete_old).await?
+            }
+            IndexCommand::Validate { schema } => index::validate(schema.as_deref(), dims)?,
+            IndexCommand::Delete { index } => index::delete(&client, &index).await?,
+            IndexCommand::Exists { index } => {
+                if index::exists(&client, &index).await? {
+                    println!("Index '{}' exists", index);
+                } else {
+                    let reason = format!("Index '{}' does not exist", index);
+                    return Err(EsError::not_found(reason).into());
+                }
+            }
+            IndexCommand::Stats { index } => index::stats(&client, &index).await?,
+        },
+        Command::Doc(command) => match command {
+            DocCommand::Put { index, id, body } => doc::put(&client, &index, &id, body).await?,
+            DocCommand::Get { index, id } => doc::get(&client, &index, &id).await?,
+            DocCommand::Delete { index, id } => doc::delete(&client, &index, &id).await?,
+        },
+        Command::Search(args) => {
+            let search_after = args
+                .search_after
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()
+                .context("--search-after must be a JSON array")?;
+            let query = SearchQuery {
+                text: args.query,
+                filters: filters(args.filters),
+                date_interval: Some(args.date_interval),
+                from: args.from,
+                size: args.size,
+                search_after,
+            };
+            let results = search::search(&client, &args.index, &query).await?;
+            search::print(&results, query.from);
+        }
+        Command::Knn(args) => {
+            let vector = query_vector(
+                embedder.as_deref(),
+                args.query.as_deref().unwrap_or_default(),
+                args.vector.as_deref(),
+            )
+            .await?;
+            let query = KnnQuery {
+                vector,
+                k: args.k,
+                num_candidates: args.num_candidates,
+                filters: filters(args.filters),
+            };
+            let results = search::knn(&client, &args.index, &query).await?;
+            search::print(&results, 0);
+        }
+        Command::Context(args) => {
+            let tokenizer = tokenizer::from_name(&config.tokenizer)?;
+            let query = ContextQuery {
+                text: args.query,
+                filters: filters(args.filters),
+                max_tokens: args.max_tokens,
+                rrf: RrfParams::default(),
+            };
+            let context = context::retrieve(
+                &client,
+                &args.index,
+                embedder.as_deref(),
+                tokenizer.as_ref(),
+                &query,
+            )
+            .await?;
+            println!("{}", context.text);
+            eprintln!(
+                "{} sections, {} of {} tokens, {} duplicates and {} over budget left out",
+                context.sections.len(),
+                context.tokens,
+                query.max_tokens,
+                context.duplicates,
+                context.truncated
+            );
+        }
+        Command::Hybrid(args) => {
+            let vector =
+                query_vector(embedder.as_deref(), &args.query, args.vector.as_deref()).await?;
+            let query = HybridQuery {
+                text: args.query,
+                vector,
+                filters: filters(args.filters),
+                size: args.size,
+                window: args.window,
+                rrf: RrfParams {
+                    rank_constant: args.rank_constant,
+                    lexical_weight: args.lexical_weight,
+                    vector_weight: args.vector_weight,
+                },
+            };
+            let results = search::hybrid(&client, &args.index, &query).await?;
+            search::print(&results, 0);
+        }
+        Command::Ingest(args) => {
+            let repo = match args.repo {
+                Some(repo) => repo,
+                None => ingest::repo_name(&args.dir)?,
+            };
+            let embedder = match embedder {
+                Some(embedder) if !args.no_embedding_cache => {
+                    Some(embed::with_cache(embedder, &config.embedding_cache)?)
+                }
+                embedder => embedder,
+            };
+            let options = IngestOptions {
+                root: args.dir,
+                index: args.index,
+                repo,
+                version: args.version,
+                tags: args.tags,
+                split: SplitOptions {
+                    max_chars: args.max_chars,
+                    overlap: args.overlap,
+                },
+                bulk: BulkOptions {
+                    max_docs: args.batch_size,
+                    max_bytes: args.batch_bytes,
+                    concurrency: args.concurrency,
                 },
-                "mappings": {
-                    "properties": {
-                        "title": { "type": "text" },
-                        "content": { "type": "text" },
-                        "date": { "type": "date" },
-                        "tags": { "type": "keyword" }
+                embedder,
+                tokenizer: tokenizer::from_name(&config.tokenizer)?,
+            };
+            let store = MetadataStore::connect(&config.metadata_url).await?;
+            let stats =
+                ingest::ingest_repo(&client, Some(&store), &options, args.git_ref.as_deref())
+                    .await?;
+            println!(
+                "'{}' has {} snippets, {} tokens",
+                options.repo, stats.snippets, stats.tokens
+            );
+        }
+        Command::Repos(command) => {
+            let store = MetadataStore::connect(&config.metadata_url).await?;
+            match command {
+                RepoCommand::List => metadata::print(&store).await?,
+                RepoCommand::Delete { name, index } => {
+                    let deleted = ingest::delete_repo(&client, &index, &name).await?;
+                    let stored = store.delete(&name).await?;
+                    if deleted == 0 && !stored {
+                        bail!("Repository '{}' does not exist", name);
                     }
+                    println!("Deleted {} chunks of '{}' from '{}'", deleted, name, index);
                 }
-            }))
-            .send()
-            .await?;
-        
-        if response.status_code().is_success() {
-            println!("Successfully created index '{}'", index_name);
-        } else {
-            println!("Failed to create index: {:?}", response.text().await?);
+            }
+        }
+        Command::Cache(command) => {
+            let cache = EmbeddingCache::open(
+                &config.embedding_cache.path,
+                config.embedding_cache.max_bytes,
+            )?;
+            match command {
+                CacheCommand::Stats => cache.print_stats()?,
+                CacheCommand::Clear => {
+                    cache.clear()?;
+                    println!(
+                        "Cleared the embedding cache at {}",
+                        config.embedding_cache.path.display()
+                    );
+                }
+            }
+        }
+        Command::Mcp { index, http } => {
+            let tokenizer = tokenizer::from_name(&config.tokenizer)?;
+            let server = mcp::McpServer::new(client, index, embedder, tokenizer);
+            match http {
+                Some(addr) => mcp::serve_http(server, addr).await?,
+                None => mcp::serve_stdio(server).await?,
+            }
+        }
+        Command::Serve {
+            index,
+            addr,
+            ingest_root,
+        } => {
+            let embedder = embedder
+                .map(|embedder| embed::with_cache(embedder, &config.embedding_cache))
+                .transpose()?;
+            let tokenizer = tokenizer::from_name(&config.tokenizer)?;
+            let store = MetadataStore::connect(&config.metadata_url).await?;
+            server::serve(client, index, embedder, tokenizer, store, ingest_root, addr).await?
+        }
+        Command::Versions { index, library } => {
+            version::print(&client, &index, library.as_deref()).await?
         }
     }
-    
+
     Ok(())
 }
+
+/// The vector passed as JSON, or `text` embedded with the configured embedder
+async fn query_vector(
+    embedder: Option<&dyn Embedder>,
+    text: &str,
+    vector: Option<&str>,
+) -> Result<Vec<f32>> {
+    if let Some(vector) = vector {
+        return serde_json::from_str(vector)
+            .context("The query vector must be a JSON array of numbers");
+    }
+    let Some(embedder) = embedder else {
+        bail!("Pass --vector or configure an embedder to embed the query");
+    };
+    embed::embed_query(embedder, text).await
+}
+
+fn filters(args: FilterArgs) -> Filters {
+    Filters {
+        library: args.library,
+        version: args.version,
+        tags: args.tags,
+        since: args.since,
+        until: args.until,
+    }
+}
@@ -0,0 +1,591 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result, bail};
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    client::EsClient,
+    context::{self, ContextQuery, DEFAULT_MAX_TOKENS},
+    embed::Embedder,
+    search::{Filters, RrfParams},
+    tokenizer::Tokenizer,
+    version,
+};
+
+const PROTOCOL_VERSION: &str = "2025-03-26";
+
+/// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Model Context Protocol server answering tool calls from the index
+#[derive(Clone)]
+pub struct McpServer {
+    client: EsClient,
+    index: String,
+    embedder: Option<Arc<dyn Embedder>>,
+    tokenizer: Arc<dyn Tokenizer>,
+}
+
+#[derive(Deserialize)]
+struct ResolveArgs {
+    library_name: String,
+}
+
+#[derive(Deserialize)]
+struct DocsArgs {
+    library: String,
+    topic: Option<String>,
+    version: Option<String>,
+    max_tokens: Option<usize>,
+    #[serde(flatten)]
+    rrf: RrfParams,
+}
+
+impl McpServer {
+    pub fn new(
+        client: EsClient,
+        index: String,
+        embedder: Option<Arc<dyn Embedder>>,
+        tokenizer: Arc<dyn Tokenizer>,
+    ) -> Self {
+        McpServer {
+            client,
+            index,
+            embedder,
+            tokenizer,
+        }
+    }
+
+    /// Answer a message or a batch of messages, `None` when there is nothing
+    /// to answer because they were all notifications
+    async fn handle_message(&self, message: Value) -> Option<Value> {
+        match message {
+            // JSON-RPC answers an empty batch with a single error, not with nothing
+            Value::Array(batch) if batch.is_empty() => {
+                Some(error_response(Value::Null, INVALID_REQUEST, "Empty batch"))
+            }
+            Value::Array(batch) => {
+                let mut responses = Vec::new();
+                for message in batch {
+                    responses.extend(self.handle(message).await);
+                }
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            message => self.handle(message).await,
+        }
+    }
+
+    async fn handle(&self, message: Value) -> Option<Value> {
+        let Some(method) = message["method"].as_str() else {
+            // A response to a request we never send, or garbage
+            if message.get("id").is_some() && message.get("result").is_none() {
+                return Some(error_response(
+                    message["id"].clone(),
+                    INVALID_REQUEST,
+                    "Missing method",
+                ));
+            }
+            return None;
+        };
+        // Notifications, such as `notifications/initialized`, have no id and get no answer
+        let id = message.get("id")?.clone();
+        let params = &message["params"];
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION")
+                }
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => self.call_tool(params).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        };
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, &message),
+        })
+    }
+
+    /// Failures of the tool itself are reported in the result so the model
+    /// sees them, only malformed calls are JSON-RPC errors
+    async fn call_tool(&self, params: &Value) -> std::result::Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let arguments = params
+            .get("arguments")
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        let invalid = |err: serde_json::Error| (INVALID_PARAMS, format!("{}: {}", name, err));
+
+        let result = match name {
+            "list_libraries" => self.list_libraries().await,
+            "resolve_library_id" => {
+                let args: ResolveArgs = serde_json::from_value(arguments).map_err(invalid)?;
+                self.resolve_library_id(&args.library_name).await
+            }
+            "get_library_docs" => {
+                let args: DocsArgs = serde_json::from_value(arguments).map_err(invalid)?;
+                args.rrf
+                    .validate()
+                    .map_err(|err| (INVALID_PARAMS, format!("{}: {:#}", name, err)))?;
+                self.get_library_docs(args).await
+            }
+            _ => return Err((INVALID_PARAMS, format!("Unknown tool '{}'", name))),
+        };
+
+        Ok(match result {
+            Ok(text) => json!({ "content": [{ "type": "text", "text": text }], "isError": false }),
+            Err(err) => json!({
+                "content": [{ "type": "text", "text": format!("{:#}", err) }],
+                "isError": true
+            }),
+        })
+    }
+
+    async fn list_libraries(&self) -> Result<String> {
+        let libraries = version::list(&self.client, &self.index, None).await?;
+        if libraries.is_empty() {
+            return Ok("No libraries are indexed yet.".to_string());
+        }
+        Ok(libraries
+            .iter()
+            .map(|(name, versions)| describe_library(name, versions))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// Indexed libraries whose name matches `query`, best match first
+    async fn resolve_library_id(&self, query: &str) -> Result<String> {
+        let libraries = version::list(&self.client, &self.index, None).await?;
+        let wanted = normalize(query);
+        let mut matches: Vec<(u8, &String, &Vec<String>)> = libraries
+            .iter()
+            .filter_map(|(name, versions)| {
+                let normalized = normalize(name);
+                let rank = if name == query {
+                    0
+                } else if normalized == wanted {
+                    1
+                } else if normalized.contains(&wanted) || wanted.contains(&normalized) {
+                    2
+                } else {
+                    return None;
+                };
+                Some((rank, name, versions))
+            })
+            .collect();
+        matches.sort_by_key(|(rank, name, _)| (*rank, name.len()));
+
+        if matches.is_empty() {
+            return Ok(format!(
+                "No indexed library matches '{}'. Call list_libraries to see every library.",
+                query
+            ));
+        }
+        Ok(matches
+            .into_iter()
+            .map(|(_, name, versions)| describe_library(name, versions))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    async fn get_library_docs(&self, args: DocsArgs) -> Result<String> {
+        // Without a topic the library name itself pulls up its overview sections
+        let query = ContextQuery {
+            text: args.topic.unwrap_or_else(|| args.library.clone()),
+            filters: Filters {
+                library: Some(args.library.clone()),
+                version: args.version,
+                ..Filters::default()
+            },
+            max_tokens: args.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            rrf: args.rrf,
+        };
+        let context = context::retrieve(
+            &self.client,
+            &self.index,
+            self.embedder.as_deref(),
+            self.tokenizer.as_ref(),
+            &query,
+        )
+        .await?;
+
+        if context.sections.is_empty() {
+            return Ok(format!("No documentation found in '{}'.", args.library));
+        }
+        Ok(context.text)
+    }
+}
+
+fn describe_library(name: &str, versions: &[String]) -> String {
+    if versions.is_empty() {
+        format!("- {}", name)
+    } else {
+        format!("- {} (versions: {})", name, versions.join(", "))
+    }
+}
+
+/// Lowercase letters and digits only, so `Next.js` matches `nextjs`
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn tools() -> Value {
+    json!([
+        {
+            "name": "list_libraries",
+            "description": "List every indexed library with its versions, newest first.",
+            "inputSchema": { "type": "object", "properties": {} }
+        },
+        {
+            "name": "resolve_library_id",
+            "description": "Find the library id to pass to get_library_docs from a library or package name.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "library_name": {
+                        "type": "string",
+                        "description": "Library name to look up, e.g. 'elasticsearch-rs'"
+                    }
+                },
+                "required": ["library_name"]
+            }
+        },
+        {
+            "name": "get_library_docs",
+            "description": "Fetch up-to-date documentation of a library, focused on a topic and limited to a token budget.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "library": {
+                        "type": "string",
+                        "description": "Library id returned by resolve_library_id"
+                    },
+                    "topic": {
+                        "type": "string",
+                        "description": "What the documentation should be about, e.g. 'bulk indexing'"
+                    },
+                    "version": {
+                        "type": "string",
+                        "description": "Exact version or requirement such as '1.x', the latest by default"
+                    },
+                    "max_tokens": {
+                        "type": "integer",
+                        "description": "Maximum size of the returned documentation in tokens",
+                        "default": DEFAULT_MAX_TOKENS
+                    },
+                    "rank_constant": {
+                        "type": "number",
+                        "description": "RRF rank constant when fusing full-text and vector rankings, greater than 0",
+                        "default": 60
+                    },
+                    "lexical_weight": {
+                        "type": "number",
+                        "description": "Weight of the full-text ranking, 0 or more",
+                        "default": 1
+                    },
+                    "vector_weight": {
+                        "type": "number",
+                        "description": "Weight of the vector ranking, 0 or more",
+                        "default": 1
+                    }
+                },
+                "required": ["library"]
+            }
+        }
+    ])
+}
+
+/// Speak MCP over stdin and stdout, one JSON-RPC message per line. Anything
+/// else has to go to stderr.
+pub async fn serve_stdio(server: McpServer) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(message) => server.handle_message(message).await,
+            Err(err) => Some(error_response(Value::Null, PARSE_ERROR, &err.to_string())),
+        };
+        if let Some(response) = response {
+            stdout
+                .write_all(format!("{}\n", response).as_bytes())
+                .await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Speak MCP over streamable HTTP on `addr`, at `POST /mcp`. Every request
+/// is answered with plain JSON, the server never opens an event stream.
+pub async fn serve_http(server: McpServer, addr: SocketAddr) -> Result<()> {
+    let app = router(server);
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    eprintln!("MCP endpoint at http://{}/mcp", addr);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .context("MCP server failed")
+}
+
+fn router(server: McpServer) -> Router {
+    Router::new()
+        .route("/mcp", post(http_message))
+        .with_state(server)
+}
+
+async fn http_message(
+    State(server): State<McpServer>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    // Browsers send an Origin, only pages served from this machine may call
+    // the tools, against DNS rebinding
+    if let Some(origin) = headers.get(header::ORIGIN)
+        && let Err(err) = check_origin(origin.to_str().unwrap_or_default())
+    {
+        return (StatusCode::FORBIDDEN, err.to_string()).into_response();
+    }
+
+    let message = match serde_json::from_str(&body) {
+        Ok(message) => message,
+        Err(err) => {
+            let response = error_response(Value::Null, PARSE_ERROR, &err.to_string());
+            return (StatusCode::BAD_REQUEST, Json(response)).into_response();
+        }
+    };
+    match server.handle_message(message).await {
+        Some(response) => Json(response).into_response(),
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+fn check_origin(origin: &str) -> Result<()> {
+    let authority = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    let authority = authority.split('/').next().unwrap_or_default();
+    let host = authority
+        .rsplit_once(':')
+        .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+        .map_or(authority, |(host, _)| host);
+    if !matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+        bail!("Origin '{}' is not allowed", origin);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        pool::tests::{closed_node, config},
+        tokenizer,
+    };
+
+    /// A server whose Elasticsearch cannot be reached
+    async fn server() -> McpServer {
+        let client = EsClient::connect(&config(&[&closed_node().await]))
+            .await
+            .unwrap();
+        McpServer::new(
+            client,
+            "docs".to_string(),
+            None,
+            tokenizer::from_name("chars").unwrap(),
+        )
+    }
+
+    fn request(id: i64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    #[tokio::test]
+    async fn initialize_announces_the_tools_capability() {
+        let response = server()
+            .await
+            .handle_message(request(1, "initialize", json!({})))
+            .await
+            .unwrap();
+
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["protocolVersion"], PROTOCOL_VERSION);
+        assert!(response["result"]["capabilities"]["tools"].is_object());
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let notification = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+
+        assert_eq!(server().await.handle_message(notification).await, None);
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_are_method_not_found() {
+        let response = server()
+            .await
+            .handle_message(request(7, "resources/list", json!({})))
+            .await
+            .unwrap();
+
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn batches_are_answered_without_their_notifications() {
+        let batch = json!([
+            request(1, "ping", json!({})),
+            { "jsonrpc": "2.0", "method": "notifications/initialized" },
+            request(2, "tools/list", json!({}))
+        ]);
+        let response = server().await.handle_message(batch).await.unwrap();
+
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"]["tools"].as_array().unwrap().len(), 3);
+
+        let notifications = json!([{ "jsonrpc": "2.0", "method": "notifications/initialized" }]);
+        assert_eq!(server().await.handle_message(notifications).await, None);
+    }
+
+    #[tokio::test]
+    async fn empty_batches_are_invalid_requests() {
+        let response = server().await.handle_message(json!([])).await.unwrap();
+
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert_eq!(response["id"], Value::Null);
+
+        let request = Request::post("/mcp").body(Body::from("[]")).unwrap();
+        let response = router(server().await).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn malformed_tool_calls_are_invalid_params() {
+        let server = server().await;
+        let calls = [
+            json!({ "name": "resolve_library_id", "arguments": {} }),
+            json!({ "name": "get_library_docs", "arguments": { "library": 1 } }),
+            json!({
+                "name": "get_library_docs",
+                "arguments": { "library": "tokio", "rank_constant": 0 }
+            }),
+            json!({
+                "name": "get_library_docs",
+                "arguments": { "library": "tokio", "vector_weight": -1 }
+            }),
+            json!({ "name": "drop_index" }),
+        ];
+        for params in calls {
+            let response = server
+                .handle_message(request(3, "tools/call", params.clone()))
+                .await
+                .unwrap();
+            assert_eq!(response["error"]["code"], INVALID_PARAMS, "{}", params);
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_failures_are_reported_in_the_result() {
+        let params = json!({ "name": "list_libraries" });
+        let response = server()
+            .await
+            .handle_message(request(4, "tools/call", params))
+            .await
+            .unwrap();
+
+        assert_eq!(response["result"]["isError"], true);
+    }
+
+    #[test]
+    fn local_origins_are_accepted() {
+        for origin in [
+            "http://localhost",
+            "http://localhost:3000",
+            "https://127.0.0.1:8443/app",
+            "http://[::1]:8080",
+        ] {
+            assert!(check_origin(origin).is_ok(), "{}", origin);
+        }
+    }
+
+    #[test]
+    fn remote_origins_are_rejected() {
+        for origin in [
+            "https://example.com",
+            "http://localhost.example.com",
+            "http://127.0.0.1.nip.io:8000",
+            "http://example.com/localhost",
+            "null",
+        ] {
+            assert!(check_origin(origin).is_err(), "{}", origin);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_requests_from_remote_origins_are_forbidden() {
+        let post = |origin: &str| {
+            Request::post("/mcp")
+                .header(header::ORIGIN, origin)
+                .body(Body::from(request(1, "ping", json!({})).to_string()))
+                .unwrap()
+        };
+        let app = router(server().await);
+
+        let response = app
+            .clone()
+            .oneshot(post("https://example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app.oneshot(post("http://localhost:3000")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["result"], json!({}));
+    }
+}
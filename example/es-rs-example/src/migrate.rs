@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::indices::{IndicesGetMappingParts, IndicesPutMappingParts};
+use serde_json::Value;
+
+use crate::{
+    client::EsClient,
+    error,
+    retry::Operation,
+    schema::{FieldMapping, IndexSchema, Mappings},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// New field, can be added to the live index
+    Add,
+    /// Setting Elasticsearch allows to change in place, e.g. `search_analyzer`
+    Update,
+    /// Change of an existing field that needs a reindex into a new index
+    Breaking,
+    /// Field only present in the live mapping. Fields cannot be removed, so it is left alone.
+    LiveOnly,
+}
+
+#[derive(Debug)]
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub detail: String,
+}
+
+/// Difference between the desired and the live mapping of an index
+#[derive(Debug, Default)]
+pub struct MappingDiff {
+    pub changes: Vec<Change>,
+    /// Put mapping body with every `Add` and `Update` change
+    pub update: Mappings,
+}
+
+impl MappingDiff {
+    pub fn is_breaking(&self) -> bool {
+        self.changes
+            .iter()
+            .any(|change| change.kind == ChangeKind::Breaking)
+    }
+
+    /// True when put mapping has nothing to do
+    pub fn is_up_to_date(&self) -> bool {
+        self.update.properties.is_empty() && self.update.extra.is_empty()
+    }
+
+    pub fn print(&self, index_name: &str) {
+        if self.changes.is_empty() {
+            println!("Mapping of '{}' matches the schema", index_name);
+            return;
+        }
+
+        println!("Mapping of '{}' differs from the schema:", index_name);
+        for change in &self.changes {
+            let (marker, note) = match change.kind {
+                ChangeKind::Add => ("+", "can be added"),
+                ChangeKind::Update => ("~", "can be updated in place"),
+                ChangeKind::Breaking => ("!", "requires a reindex"),
+                ChangeKind::LiveOnly => ("-", "only in the live mapping, left alone"),
+            };
+            println!("  {} {}: {} ({})", marker, change.path, change.detail, note);
+        }
+    }
+}
+
+/// Compare the desired mapping with the live one, field by field
+pub fn diff(desired: &Mappings, live: &Mappings) -> MappingDiff {
+    let mut changes = Vec::new();
+    let properties = diff_properties("", &desired.properties, &live.properties, &mut changes);
+
+    // Mapping level options such as `dynamic`, Elasticsearch rejects the ones
+    // that cannot be changed in place
+    let mut extra = BTreeMap::new();
+    for (option, value) in &desired.extra {
+        if live.extra.get(option) != Some(value) {
+            changes.push(Change {
+                path: option.clone(),
+                kind: ChangeKind::Update,
+                detail: format!("{} -> {}", describe_value(live.extra.get(option)), value),
+            });
+            extra.insert(option.clone(), value.clone());
+        }
+    }
+
+    MappingDiff {
+        changes,
+        update: Mappings { properties, extra },
+    }
+}
+
+fn diff_properties(
+    prefix: &str,
+    desired: &BTreeMap<String, FieldMapping>,
+    live: &BTreeMap<String, FieldMapping>,
+    changes: &mut Vec<Change>,
+) -> BTreeMap<String, FieldMapping> {
+    let mut update = BTreeMap::new();
+
+    for (name, want) in desired {
+        let path = join_path(prefix, name);
+
+        let Some(have) = live.get(name) else {
+            changes.push(Change {
+                path,
+                kind: ChangeKind::Add,
+                detail: want.field_type.to_string(),
+            });
+            update.insert(name.clone(), want.clone());
+            continue;
+        };
+
+        if have.field_type != want.field_type {
+            changes.push(Change {
+                path,
+                kind: ChangeKind::Breaking,
+                detail: format!("type {} -> {}", have.field_type, want.field_type),
+            });
+            continue;
+        }
+
+        let mut breaking = Vec::new();
+        if have.analyzer != want.analyzer {
+            breaking.push(format!(
+                "analyzer {} -> {}",
+                describe(&have.analyzer),
+                describe(&want.analyzer)
+            ));
+        }
+        if have.format != want.format {
+            breaking.push(format!(
+                "format {} -> {}",
+                describe(&have.format),
+                describe(&want.format)
+            ));
+        }
+        if want.dims.is_some() && have.dims != want.dims {
+            breaking.push(format!(
+                "dims {} -> {}",
+                describe(&have.dims.map(|dims| dims.to_string())),
+                describe(&want.dims.map(|dims| dims.to_string()))
+            ));
+        }
+        if want.similarity.is_some() && have.similarity != want.similarity {
+            breaking.push(format!(
+                "similarity {} -> {}",
+                describe(&have.similarity),
+                describe(&want.similarity)
+            ));
+        }
+        for detail in breaking {
+            changes.push(Change {
+                path: path.clone(),
+                kind: ChangeKind::Breaking,
+                detail,
+            });
+        }
+
+        if have.search_analyzer != want.search_analyzer {
+            changes.push(Change {
+                path: path.clone(),
+                kind: ChangeKind::Update,
+                detail: format!(
+                    "search_analyzer {} -> {}",
+                    describe(&have.search_analyzer),
+                    describe(&want.search_analyzer)
+                ),
+            });
+            update.insert(name.clone(), want.clone());
+        }
+
+        // Elasticsearch rejects the put mapping when one of these cannot be
+        // changed in place, which beats ignoring it
+        for (option, value) in &want.extra {
+            if have.extra.get(option) != Some(value) {
+                changes.push(Change {
+                    path: path.clone(),
+                    kind: ChangeKind::Update,
+                    detail: format!(
+                        "{} {} -> {}",
+                        option,
+                        describe_value(have.extra.get(option)),
+                        value
+                    ),
+                });
+                update.insert(name.clone(), want.clone());
+            }
+        }
+
+        let children = diff_properties(&path, &want.properties, &have.properties, changes);
+        if !children.is_empty() {
+            // Keeps the field's own updates, if it has any
+            update
+                .entry(name.clone())
+                .or_insert_with(|| FieldMapping::new(want.field_type.clone()))
+                .properties = children;
+        }
+    }
+
+    for (name, have) in live {
+        if !desired.contains_key(name) {
+            changes.push(Change {
+                path: join_path(prefix, name),
+                kind: ChangeKind::LiveOnly,
+                detail: have.field_type.to_string(),
+            });
+        }
+    }
+
+    update
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+fn describe(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("(default)")
+}
+
+fn describe_value(value: Option<&Value>) -> String {
+    value.map_or("(default)".to_string(), Value::to_string)
+}
+
+/// Fetch the live mapping of `index_name`, which may also be an alias
+pub async fn live_mappings(client: &EsClient, index_name: &str) -> Result<Mappings> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.indices()
+                .get_mapping(IndicesGetMappingParts::Index(&[index_name]))
+                .send()
+                .await
+        })
+        .await?;
+
+    let response = error::check(response)
+        .await
+        .context("Failed to get mapping")?;
+
+    // The response is keyed by concrete index name, an alias must point at a single index
+    let body: Value = response.json().await?;
+    let mut indices = body
+        .as_object()
+        .into_iter()
+        .flat_map(|indices| indices.values());
+    let (Some(index), None) = (indices.next(), indices.next()) else {
+        bail!("'{}' must resolve to exactly one index", index_name);
+    };
+
+    Ok(serde_json::from_value(index["mappings"].clone())?)
+}
+
+/// Print how the live mapping of `index_name` differs from `schema`
+pub async fn check(
+    client: &EsClient,
+    index_name: &str,
+    schema: &IndexSchema,
+) -> Result<MappingDiff> {
+    let live = live_mappings(client, index_name).await?;
+    let diff = diff(&schema.mappings, &live);
+    diff.print(index_name);
+    Ok(diff)
+}
+
+/// Bring the live mapping in line with `schema` using put mapping. Nothing is
+/// changed when the schema contains a breaking change.
+pub async fn migrate(
+    client: &EsClient,
+    index_name: &str,
+    schema: &IndexSchema,
+    dry_run: bool,
+) -> Result<()> {
+    let diff = check(client, index_name, schema).await?;
+
+    if diff.is_breaking() {
+        bail!(
+            "The mapping of '{}' cannot be migrated in place, reindex into a new index instead",
+            index_name
+        );
+    }
+    if diff.is_up_to_date() {
+        return Ok(());
+    }
+    if dry_run {
+        println!("Dry run, would send:");
+        println!("{}", serde_json::to_string_pretty(&diff.update)?);
+        return Ok(());
+    }
+
+    let update = &diff.update;
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.indices()
+                .put_mapping(IndicesPutMappingParts::Index(&[index_name]))
+                .body(update)
+                .send()
+                .await
+        })
+        .await?;
+
+    error::check(response)
+        .await
+        .context("Failed to update mapping")?;
+
+    println!("Updated mapping of '{}'", index_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn mappings(value: Value) -> Mappings {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn kinds(diff: &MappingDiff) -> Vec<(&str, ChangeKind)> {
+        diff.changes
+            .iter()
+            .map(|change| (change.path.as_str(), change.kind))
+            .collect()
+    }
+
+    #[test]
+    fn identical_mappings_have_no_changes() {
+        let live = mappings(json!({ "properties": { "title": { "type": "text" } } }));
+        let diff = diff(&live, &live);
+
+        assert!(diff.changes.is_empty());
+        assert!(diff.is_up_to_date());
+        assert!(!diff.is_breaking());
+    }
+
+    #[test]
+    fn mapping_level_options_are_updated() {
+        let live = mappings(json!({ "dynamic": "true", "properties": {} }));
+        let desired = mappings(json!({ "dynamic": "strict", "_source": { "enabled": true } }));
+        let diff = diff(&desired, &live);
+
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("_source", ChangeKind::Update),
+                ("dynamic", ChangeKind::Update)
+            ]
+        );
+        assert!(!diff.is_up_to_date());
+        assert_eq!(
+            serde_json::to_value(&diff.update).unwrap(),
+            json!({ "properties": {}, "dynamic": "strict", "_source": { "enabled": true } })
+        );
+    }
+
+    #[test]
+    fn new_fields_are_added() {
+        let live = mappings(json!({ "properties": {
+            "title": { "type": "text" },
+            "meta": { "type": "object", "properties": { "a": { "type": "keyword" } } }
+        } }));
+        let desired = mappings(json!({ "properties": {
+            "title": { "type": "text" },
+            "tags": { "type": "keyword" },
+            "meta": { "type": "object", "properties": {
+                "a": { "type": "keyword" },
+                "b": { "type": "long" }
+            } }
+        } }));
+        let diff = diff(&desired, &live);
+
+        assert_eq!(
+            kinds(&diff),
+            [("meta.b", ChangeKind::Add), ("tags", ChangeKind::Add)]
+        );
+        assert!(!diff.is_breaking());
+        assert_eq!(
+            serde_json::to_value(&diff.update).unwrap(),
+            json!({ "properties": {
+                "meta": { "type": "object", "properties": { "b": { "type": "long" } } },
+                "tags": { "type": "keyword" }
+            } })
+        );
+    }
+
+    #[test]
+    fn type_analyzer_and_dims_changes_are_breaking() {
+        let live = mappings(json!({ "properties": {
+            "count": { "type": "integer" },
+            "body": { "type": "text", "analyzer": "standard" },
+            "embedding": { "type": "dense_vector", "dims": 384 }
+        } }));
+        let desired = mappings(json!({ "properties": {
+            "count": { "type": "long" },
+            "body": { "type": "text", "analyzer": "english" },
+            "embedding": { "type": "dense_vector", "dims": 1024 }
+        } }));
+        let diff = diff(&desired, &live);
+
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("body", ChangeKind::Breaking),
+                ("count", ChangeKind::Breaking),
+                ("embedding", ChangeKind::Breaking)
+            ]
+        );
+        assert!(diff.is_breaking());
+        assert!(diff.is_up_to_date());
+    }
+
+    #[test]
+    fn search_analyzer_is_updated_in_place() {
+        let live = mappings(json!({ "properties": {
+            "body": { "type": "text", "analyzer": "standard" },
+            "legacy": { "type": "keyword" }
+        } }));
+        let desired = mappings(json!({ "properties": {
+            "body": { "type": "text", "analyzer": "standard", "search_analyzer": "simple" }
+        } }));
+        let diff = diff(&desired, &live);
+
+        assert_eq!(
+            kinds(&diff),
+            [
+                ("body", ChangeKind::Update),
+                ("legacy", ChangeKind::LiveOnly)
+            ]
+        );
+        assert!(!diff.is_breaking());
+        assert!(diff.update.properties.contains_key("body"));
+        assert!(!diff.update.properties.contains_key("legacy"));
+    }
+
+    #[test]
+    fn changed_pass_through_options_are_updates() {
+        let live = mappings(json!({ "properties": { "name": { "type": "keyword" } } }));
+        let desired = mappings(json!({ "properties": {
+            "name": { "type": "keyword", "ignore_above": 256 }
+        } }));
+        let diff = diff(&desired, &live);
+
+        assert_eq!(kinds(&diff), [("name", ChangeKind::Update)]);
+        assert_eq!(
+            serde_json::to_value(&diff.update).unwrap(),
+            json!({ "properties": { "name": { "type": "keyword", "ignore_above": 256 } } })
+        );
+    }
+}
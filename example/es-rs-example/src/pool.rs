@@ -0,0 +1,439 @@
+use std::{
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::{Elasticsearch, http::Url, nodes::NodesInfoParts};
+use serde_json::Value;
+
+use crate::{client, config::Config};
+
+/// How long a node is skipped after its first failure, doubled on every
+/// consecutive failure up to `MAX_DEAD_TIMEOUT`
+const DEAD_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_DEAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+pub struct Node {
+    pub url: Url,
+    pub client: Elasticsearch,
+    state: Mutex<NodeState>,
+}
+
+#[derive(Default)]
+struct NodeState {
+    failures: u32,
+    dead_until: Option<Instant>,
+}
+
+impl Node {
+    fn new(url: Url, config: &Config) -> Result<Self> {
+        Ok(Node {
+            client: client::node_client(url.clone(), config)?,
+            url,
+            state: Mutex::new(NodeState::default()),
+        })
+    }
+
+    fn dead_until(&self) -> Option<Instant> {
+        self.state.lock().unwrap().dead_until
+    }
+}
+
+/// The set of nodes requests are spread over. Nodes are picked round-robin,
+/// nodes that fail with connection errors are taken out of rotation for a
+/// while and retried once their timeout expires.
+pub struct NodePool {
+    nodes: RwLock<Vec<Arc<Node>>>,
+    next: AtomicUsize,
+    config: Config,
+    /// Refresh the node list from `_nodes/http` this often, `None` keeps the list static
+    sniff_interval: Option<Duration>,
+    last_sniff: Mutex<Option<Instant>>,
+}
+
+impl NodePool {
+    pub fn new(config: &Config) -> Result<Self> {
+        if config.nodes.is_empty() {
+            bail!("At least one Elasticsearch node URL is required");
+        }
+        let nodes = config
+            .nodes
+            .iter()
+            .map(|raw| {
+                let url = Url::parse(raw)
+                    .with_context(|| format!("Invalid Elasticsearch URL '{}'", raw))?;
+                Node::new(url, config).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(NodePool {
+            nodes: RwLock::new(nodes),
+            next: AtomicUsize::new(0),
+            config: config.clone(),
+            sniff_interval: config.sniff.then_some(config.sniff_interval),
+            last_sniff: Mutex::new(None),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.read().unwrap().len()
+    }
+
+    /// Next live node in round-robin order. When every node is dead the one
+    /// that is due to be resurrected first is tried anyway.
+    pub fn select(&self) -> Arc<Node> {
+        let nodes = self.nodes.read().unwrap();
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        (0..nodes.len())
+            .map(|offset| &nodes[(start + offset) % nodes.len()])
+            .find(|node| node.dead_until().is_none_or(|until| until <= now))
+            .or_else(|| nodes.iter().min_by_key(|node| node.dead_until()))
+            .cloned()
+            .expect("node pool is never empty")
+    }
+
+    pub fn mark_dead(&self, node: &Node) {
+        let mut state = node.state.lock().unwrap();
+        let timeout = DEAD_TIMEOUT
+            .saturating_mul(2u32.saturating_pow(state.failures))
+            .min(MAX_DEAD_TIMEOUT);
+        state.failures += 1;
+        state.dead_until = Some(Instant::now() + timeout);
+        eprintln!("Node {} marked dead for {:?}", node.url, timeout);
+    }
+
+    pub fn mark_alive(&self, node: &Node) {
+        let mut state = node.state.lock().unwrap();
+        if state.dead_until.is_some() {
+            eprintln!("Node {} is alive again", node.url);
+        }
+        *state = NodeState::default();
+    }
+
+    /// Re-discover the cluster if sniffing is enabled and the interval has passed
+    pub async fn sniff_if_due(&self) {
+        let Some(interval) = self.sniff_interval else {
+            return;
+        };
+        {
+            let mut last_sniff = self.last_sniff.lock().unwrap();
+            if last_sniff.is_some_and(|last| last.elapsed() < interval) {
+                return;
+            }
+            *last_sniff = Some(Instant::now());
+        }
+
+        if let Err(err) = self.sniff().await {
+            eprintln!("Sniffing cluster nodes failed: {:#}", err);
+        }
+    }
+
+    /// Replace the node list with the HTTP publish addresses the cluster reports.
+    /// Known nodes keep their client and dead/alive state.
+    async fn sniff(&self) -> Result<()> {
+        let seed = self.select();
+        let response = seed
+            .client
+            .nodes()
+            .info(NodesInfoParts::Metric(&["http"]))
+            .send()
+            .await?;
+        if !response.status_code().is_success() {
+            bail!("Node info request returned {}", response.status_code());
+        }
+
+        let body: Value = response.json().await?;
+        let mut urls = Vec::new();
+        for info in body["nodes"]
+            .as_object()
+            .into_iter()
+            .flat_map(|nodes| nodes.values())
+        {
+            if let Some(address) = info["http"]["publish_address"].as_str() {
+                urls.push(publish_url(&seed.url, address)?);
+            }
+        }
+        if urls.is_empty() {
+            bail!("Cluster reported no HTTP enabled nodes");
+        }
+
+        let current = self.nodes.read().unwrap().clone();
+        let mut nodes = Vec::with_capacity(urls.len());
+        for url in urls {
+            match current.iter().find(|node| node.url == url) {
+                Some(node) => nodes.push(node.clone()),
+                None => nodes.push(Arc::new(Node::new(url, &self.config)?)),
+            }
+        }
+        *self.nodes.write().unwrap() = nodes;
+
+        Ok(())
+    }
+}
+
+/// Publish addresses look like `10.0.0.1:9200` or `host.example/10.0.0.1:9200`.
+/// The host name is preferred so TLS host name verification keeps working.
+fn publish_url(seed: &Url, address: &str) -> Result<Url> {
+    let host_port = match address.split_once('/') {
+        Some((host, ip_port)) if !host.is_empty() => {
+            let port = ip_port
+                .rsplit_once(':')
+                .map(|(_, port)| port)
+                .unwrap_or("9200");
+            format!("{}:{}", host, port)
+        }
+        Some((_, ip_port)) => ip_port.to_string(),
+        None => address.to_string(),
+    };
+
+    Url::parse(&format!("{}://{}", seed.scheme(), host_port))
+        .with_context(|| format!("Invalid publish address '{}'", address))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::{CacheConfig, TlsConfig},
+        retry::RetryPolicy,
+    };
+
+    /// A local HTTP server answering every request with `200 {"name": name}`,
+    /// returns its URL and the number of requests it served
+    pub(crate) async fn mock_node(name: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let body = format!(r#"{{"name":"{}"}}"#, name);
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                         content-length: {}\r\nconnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+        (url, served)
+    }
+
+    /// A request received by `mock_server`
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockRequest {
+        pub method: String,
+        /// Path and query string
+        pub path: String,
+        pub body: String,
+    }
+
+    impl MockRequest {
+        pub(crate) fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    /// A local HTTP server answering every request with the status and JSON
+    /// body `respond` returns for it, returns its URL and the requests it received
+    pub(crate) async fn mock_server<F>(respond: F) -> (String, Arc<Mutex<Vec<MockRequest>>>)
+    where
+        F: Fn(&MockRequest) -> (u16, String) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (received, respond) = (received.clone(), respond.clone());
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let (status, body) = respond(&request);
+                    received.lock().unwrap().push(request);
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\n\
+                         content-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.ok();
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<MockRequest> {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let header_end = loop {
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+        let content_length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0);
+        while request.len() < header_end + content_length {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        let mut request_line = head.split_whitespace();
+        Some(MockRequest {
+            method: request_line.next()?.to_string(),
+            path: request_line.next()?.to_string(),
+            body: String::from_utf8_lossy(&request[header_end..]).to_string(),
+        })
+    }
+
+    /// URL of a port nothing listens on, connecting to it is refused
+    pub(crate) async fn closed_node() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    pub(crate) fn config(nodes: &[&str]) -> Config {
+        Config {
+            nodes: nodes.iter().map(|node| node.to_string()).collect(),
+            sniff: false,
+            sniff_interval: Duration::from_secs(60),
+            timeout: Some(Duration::from_secs(5)),
+            auth: None,
+            tls: TlsConfig::default(),
+            retry: RetryPolicy::default(),
+            embedding: None,
+            embedding_cache: CacheConfig {
+                path: PathBuf::from("unused"),
+                max_bytes: 0,
+            },
+            tokenizer: "chars".to_string(),
+            metadata_url: "sqlite::memory:".to_string(),
+        }
+    }
+
+    fn selected(pool: &NodePool, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| pool.select().url.port().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn requests_are_spread_round_robin() {
+        let (a, served_a) = mock_node("a").await;
+        let (b, served_b) = mock_node("b").await;
+        let (c, served_c) = mock_node("c").await;
+        let pool = NodePool::new(&config(&[&a, &b, &c])).unwrap();
+
+        for _ in 0..6 {
+            let node = pool.select();
+            let response = node.client.info().send().await.unwrap();
+            assert!(response.status_code().is_success());
+        }
+
+        for served in [served_a, served_b, served_c] {
+            assert_eq!(served.load(Ordering::SeqCst), 2);
+        }
+    }
+
+    #[test]
+    fn dead_nodes_are_skipped_until_resurrected() {
+        let urls = ["http://127.0.0.1:9201", "http://127.0.0.1:9202"];
+        let pool = NodePool::new(&config(&urls)).unwrap();
+        let dead = pool.nodes.read().unwrap()[0].clone();
+
+        pool.mark_dead(&dead);
+        assert!(selected(&pool, 4).iter().all(|port| port == "9202"));
+
+        // The timeout has expired
+        dead.state.lock().unwrap().dead_until = Some(Instant::now() - Duration::from_millis(1));
+        let ports = selected(&pool, 4);
+        assert!(ports.iter().any(|port| port == "9201"));
+        assert!(ports.iter().any(|port| port == "9202"));
+
+        pool.mark_alive(&dead);
+        let state = dead.state.lock().unwrap();
+        assert_eq!(state.failures, 0);
+        assert!(state.dead_until.is_none());
+    }
+
+    #[test]
+    fn dead_timeout_doubles_on_consecutive_failures() {
+        let pool = NodePool::new(&config(&["http://127.0.0.1:9201"])).unwrap();
+        let node = pool.select();
+
+        pool.mark_dead(&node);
+        let first = node.dead_until().unwrap();
+        pool.mark_dead(&node);
+        let second = node.dead_until().unwrap();
+
+        assert!(second - first > DEAD_TIMEOUT);
+        assert!(second <= Instant::now() + DEAD_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn all_dead_picks_the_first_to_resurrect() {
+        let urls = ["http://127.0.0.1:9201", "http://127.0.0.1:9202"];
+        let pool = NodePool::new(&config(&urls)).unwrap();
+        let nodes = pool.nodes.read().unwrap().clone();
+
+        pool.mark_dead(&nodes[1]);
+        pool.mark_dead(&nodes[0]);
+        pool.mark_dead(&nodes[0]);
+
+        assert!(selected(&pool, 3).iter().all(|port| port == "9202"));
+    }
+
+    #[test]
+    fn publish_url_prefers_the_host_name() {
+        let seed = Url::parse("https://seed:9200").unwrap();
+        let cases = [
+            ("10.0.0.1:9200", "https://10.0.0.1:9200/"),
+            ("es-1.example/10.0.0.1:9201", "https://es-1.example:9201/"),
+            ("/10.0.0.2:9200", "https://10.0.0.2:9200/"),
+        ];
+        for (address, expected) in cases {
+            assert_eq!(publish_url(&seed, address).unwrap().as_str(), expected);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::{
+    CountParts,
+    indices::{IndicesCreateParts, IndicesGetAliasParts, IndicesPutSettingsParts},
+    tasks::TasksGetParts,
+};
+use serde_json::{Value, json};
+
+use crate::{client::EsClient, error, index, retry::Operation, schema::IndexSchema};
+
+/// How often a running reindex task is polled
+const TASK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Physical index holding version `version` of `alias`, e.g. `docs_v2`
+pub fn versioned_name(alias: &str, version: u32) -> String {
+    format!("{}_v{}", alias, version)
+}
+
+fn parse_version(alias: &str, index_name: &str) -> Option<u32> {
+    index_name
+        .strip_prefix(alias)?
+        .strip_prefix("_v")?
+        .parse()
+        .ok()
+}
+
+/// The physical index `alias` currently points at, `None` if the alias does not exist
+pub async fn resolve_alias(client: &EsClient, alias: &str) -> Result<Option<String>> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.indices()
+                .get_alias(IndicesGetAliasParts::Name(&[alias]))
+                .send()
+                .await
+        })
+        .await?;
+
+    if response.status_code() == 404 {
+        return Ok(None);
+    }
+    let response = error::check(response)
+        .await
+        .with_context(|| format!("Failed to resolve alias '{}'", alias))?;
+
+    let body: Value = response.json().await?;
+    let indices: Vec<&String> = body
+        .as_object()
+        .into_iter()
+        .flat_map(|indices| indices.keys())
+        .collect();
+    match indices.as_slice() {
+        [index] => Ok(Some(index.to_string())),
+        _ => bail!(
+            "Alias '{}' points at {} indices, expected exactly one",
+            alias,
+            indices.len()
+        ),
+    }
+}
+
+/// Create `<alias>_v1` and point `alias` at it
+pub async fn init(client: &EsClient, alias: &str, schema: &IndexSchema) -> Result<()> {
+    if let Some(current) = resolve_alias(client, alias).await? {
+        println!("Alias '{}' already points at '{}'", alias, current);
+        return Ok(());
+    }
+    if index::exists(client, alias).await? {
+        bail!(
+            "'{}' is a concrete index, reindex it into '{}' before using the name as an alias",
+            alias,
+            versioned_name(alias, 1)
+        );
+    }
+
+    let target = versioned_name(alias, 1);
+    create_versioned(client, &target, Some(alias), schema).await?;
+    println!("Created '{}' behind alias '{}'", target, alias);
+    Ok(())
+}
+
+async fn create_versioned(
+    client: &EsClient,
+    index_name: &str,
+    alias: Option<&str>,
+    schema: &IndexSchema,
+) -> Result<()> {
+    let mut body = serde_json::to_value(schema)?;
+    if let Some(alias) = alias {
+        body["aliases"][alias] = json!({ "is_write_index": true });
+    }
+    let body = &body;
+
+    let response = client
+        .send(Operation::NonIdempotent, |es| async move {
+            es.indices()
+                .create(IndicesCreateParts::Index(index_name))
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+
+    error::check(response)
+        .await
+        .with_context(|| format!("Failed to create index '{}'", index_name))?;
+    Ok(())
+}
+
+/// Build the next version of `alias` with `schema`, copy every document over,
+/// check the counts and atomically move the alias. Searches keep hitting the
+/// old index until the swap, writes to it are blocked while documents are copied.
+pub async fn reindex(
+    client: &EsClient,
+    alias: &str,
+    schema: &IndexSchema,
+    delete_old: bool,
+) -> Result<()> {
+    let Some(source) = resolve_alias(client, alias).await? else {
+        bail!(
+            "Alias '{}' does not exist, run `index init {}` first",
+            alias,
+            alias
+        );
+    };
+    let version = parse_version(alias, &source).with_context(|| {
+        format!(
+            "'{}' does not follow the <alias>_v<version> naming scheme",
+            source
+        )
+    })?;
+    let target = versioned_name(alias, version + 1);
+
+    // Nothing to undo when the create fails, the index may not even be ours
+    println!("Creating '{}'...", target);
+    create_versioned(client, &target, None, schema).await?;
+    if let Err(err) = copy_and_swap(client, alias, &source, &target).await {
+        rollback(client, alias, &source, &target).await;
+        return Err(err);
+    }
+    println!("Alias '{}' now points at '{}'", alias, target);
+
+    if delete_old {
+        index::delete(client, &source).await?;
+    } else {
+        set_write_block(client, &source, false).await?;
+        println!(
+            "Kept '{}', delete it once the new version is verified",
+            source
+        );
+    }
+
+    Ok(())
+}
+
+/// Block writes to `source`, copy it into `target` and point `alias` at `target`
+async fn copy_and_swap(client: &EsClient, alias: &str, source: &str, target: &str) -> Result<()> {
+    set_write_block(client, source, true).await?;
+    copy_and_verify(client, source, target).await?;
+    swap_alias(client, alias, source, target).await
+}
+
+/// Leave the old index writable and untouched behind the alias and drop the
+/// new one. Failures are only reported, the error that caused the rollback
+/// is the one worth returning.
+async fn rollback(client: &EsClient, alias: &str, source: &str, target: &str) {
+    if let Err(err) = set_write_block(client, source, false).await {
+        eprintln!(
+            "Rollback: failed to unblock writes to '{}': {:#}",
+            source, err
+        );
+    }
+    // The swap may have been applied with only its response lost, the new
+    // index must not be deleted from under the alias then
+    match resolve_alias(client, alias).await {
+        Ok(Some(current)) if current == target => eprintln!(
+            "Rollback: alias '{}' already points at '{}', kept it",
+            alias, target
+        ),
+        Ok(_) => {
+            if let Err(err) = index::delete(client, target).await {
+                eprintln!("Rollback: failed to delete '{}': {:#}", target, err);
+            }
+        }
+        Err(err) => eprintln!(
+            "Rollback: kept '{}', failed to check where alias '{}' points: {:#}",
+            target, alias, err
+        ),
+    }
+}
+
+async fn copy_and_verify(client: &EsClient, source: &str, target: &str) -> Result<()> {
+    println!("Copying documents from '{}' to '{}'...", source, target);
+    let body = json!({
+        "source": { "index": source },
+        "dest": { "index": target }
+    });
+    let body = &body;
+
+    let response = client
+        .send(Operation::NonIdempotent, |es| async move {
+            es.reindex()
+                .wait_for_completion(false)
+                .refresh(true)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let response = error::check(response)
+        .await
+        .context("Failed to start reindex")?;
+
+    let started: Value = response.json().await?;
+    let task = started["task"]
+        .as_str()
+        .context("Reindex response has no task id")?;
+    let result = wait_for_task(client, task).await?;
+    if let Some(failures) = result["failures"]
+        .as_array()
+        .filter(|failures| !failures.is_empty())
+    {
+        bail!(
+            "Reindex reported {} failures: {}",
+            failures.len(),
+            failures[0]
+        );
+    }
+
+    let source_count = count(client, source).await?;
+    let target_count = count(client, target).await?;
+    if source_count != target_count {
+        bail!(
+            "Document counts differ after reindex: '{}' has {}, '{}' has {}",
+            source,
+            source_count,
+            target,
+            target_count
+        );
+    }
+    println!("Copied {} documents", target_count);
+
+    Ok(())
+}
+
+/// Poll a background task until it completes and return its response
+async fn wait_for_task(client: &EsClient, task: &str) -> Result<Value> {
+    loop {
+        let response = client
+            .send(Operation::Read, |es| async move {
+                es.tasks().get(TasksGetParts::TaskId(task)).send().await
+            })
+            .await?;
+        let response = error::check(response)
+            .await
+            .with_context(|| format!("Failed to get task '{}'", task))?;
+
+        let body: Value = response.json().await?;
+        if body["completed"].as_bool() == Some(true) {
+            if !body["error"].is_null() {
+                bail!("Task '{}' failed: {}", task, body["error"]);
+            }
+            return Ok(body["response"].clone());
+        }
+
+        let status = &body["task"]["status"];
+        println!("  {} / {} documents", status["created"], status["total"]);
+        tokio::time::sleep(TASK_POLL_INTERVAL).await;
+    }
+}
+
+pub async fn count(client: &EsClient, index_name: &str) -> Result<u64> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.count(CountParts::Index(&[index_name])).send().await
+        })
+        .await?;
+    let response = error::check(response)
+        .await
+        .with_context(|| format!("Failed to count '{}'", index_name))?;
+
+    let body: Value = response.json().await?;
+    body["count"]
+        .as_u64()
+        .context("Count response has no count")
+}
+
+async fn set_write_block(client: &EsClient, index_name: &str, blocked: bool) -> Result<()> {
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.indices()
+                .put_settings(IndicesPutSettingsParts::Index(&[index_name]))
+                .body(json!({ "index.blocks.write": blocked }))
+                .send()
+                .await
+        })
+        .await?;
+
+    error::check(response)
+        .await
+        .with_context(|| format!("Failed to update write block on '{}'", index_name))?;
+    Ok(())
+}
+
+/// Move `alias` from `from` to `to` in a single atomic request
+async fn swap_alias(client: &EsClient, alias: &str, from: &str, to: &str) -> Result<()> {
+    let body = json!({
+        "actions": [
+            { "remove": { "index": from, "alias": alias } },
+            { "add": { "index": to, "alias": alias, "is_write_index": true } }
+        ]
+    });
+    let body = &body;
+
+    let response = client
+        .send(Operation::Idempotent, |es| async move {
+            es.indices().update_aliases().body(body).send().await
+        })
+        .await?;
+
+    error::check(response)
+        .await
+        .with_context(|| format!("Failed to swap alias '{}'", alias))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::pool::tests::{MockRequest, config, mock_server};
+
+    /// How the mock cluster behaves once documents are copied to `docs_v2`
+    #[derive(Clone, Copy)]
+    struct Scenario {
+        target_count: u64,
+        swap_status: u16,
+        /// Whether the alias moves, also when the swap answers an error
+        swap_applies: bool,
+    }
+
+    const SUCCESS: Scenario = Scenario {
+        target_count: 10,
+        swap_status: 200,
+        swap_applies: true,
+    };
+
+    /// A cluster with `docs` pointing at `docs_v1`, which holds 10 documents
+    async fn cluster(scenario: Scenario) -> (EsClient, Arc<Mutex<Vec<MockRequest>>>) {
+        let current = Mutex::new("docs_v1".to_string());
+        let (url, requests) = mock_server(move |request| {
+            let path = request.path.split('?').next().unwrap();
+            let ok = json!({ "acknowledged": true }).to_string();
+            match (request.method.as_str(), path) {
+                ("GET", "/_alias/docs") => {
+                    let current = current.lock().unwrap().clone();
+                    (
+                        200,
+                        json!({ current: { "aliases": { "docs": {} } } }).to_string(),
+                    )
+                }
+                ("POST", "/_reindex") => (200, json!({ "task": "node:1" }).to_string()),
+                ("GET", path) if path.starts_with("/_tasks/") => (
+                    200,
+                    json!({ "completed": true, "response": { "failures": [] } }).to_string(),
+                ),
+                (_, "/docs_v1/_count") => (200, json!({ "count": 10 }).to_string()),
+                (_, "/docs_v2/_count") => {
+                    (200, json!({ "count": scenario.target_count }).to_string())
+                }
+                ("POST", "/_aliases") => {
+                    if scenario.swap_applies {
+                        *current.lock().unwrap() = "docs_v2".to_string();
+                    }
+                    let body = if scenario.swap_status == 200 {
+                        ok
+                    } else {
+                        json!({ "error": { "type": "exception", "reason": "lost" } }).to_string()
+                    };
+                    (scenario.swap_status, body)
+                }
+                ("PUT", _) | ("DELETE", _) => (200, ok),
+                _ => (400, json!({ "error": "unexpected request" }).to_string()),
+            }
+        })
+        .await;
+        let client = EsClient::connect(&config(&[&url])).await.unwrap();
+        (client, requests)
+    }
+
+    /// Method and path of every request but the read-only ones
+    fn writes(requests: &Mutex<Vec<MockRequest>>) -> Vec<String> {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.method != "GET" && !request.path.contains("/_count"))
+            .map(|request| {
+                let path = request.path.split('?').next().unwrap();
+                format!("{} {}", request.method, path)
+            })
+            .collect()
+    }
+
+    /// `index.blocks.write` of every settings update of `index_name`, in order
+    fn write_blocks(requests: &Mutex<Vec<MockRequest>>, index_name: &str) -> Vec<bool> {
+        let path = format!("/{}/_settings", index_name);
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.path == path)
+            .map(|request| request.json()["index.blocks.write"].as_bool().unwrap())
+            .collect()
+    }
+
+    async fn run(scenario: Scenario, delete_old: bool) -> (Result<()>, Vec<String>, Vec<bool>) {
+        let (client, requests) = cluster(scenario).await;
+        let result = reindex(&client, "docs", &IndexSchema::docs_with_dims(8), delete_old).await;
+        (
+            result,
+            writes(&requests),
+            write_blocks(&requests, "docs_v1"),
+        )
+    }
+
+    #[tokio::test]
+    async fn failed_swap_unblocks_the_source_and_deletes_the_target() {
+        let scenario = Scenario {
+            swap_status: 500,
+            swap_applies: false,
+            ..SUCCESS
+        };
+        let (result, writes, blocks) = run(scenario, true).await;
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("Failed to swap alias"));
+        assert_eq!(
+            writes,
+            [
+                "PUT /docs_v2",
+                "PUT /docs_v1/_settings",
+                "POST /_reindex",
+                "POST /_aliases",
+                "PUT /docs_v1/_settings",
+                "DELETE /docs_v2",
+            ]
+        );
+        assert_eq!(blocks, [true, false]);
+    }
+
+    #[tokio::test]
+    async fn count_mismatch_aborts_before_the_swap() {
+        let scenario = Scenario {
+            target_count: 9,
+            ..SUCCESS
+        };
+        let (result, writes, blocks) = run(scenario, true).await;
+
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Document counts differ")
+        );
+        assert!(!writes.contains(&"POST /_aliases".to_string()));
+        assert_eq!(writes.last().unwrap(), "DELETE /docs_v2");
+        assert_eq!(blocks, [true, false]);
+    }
+
+    #[tokio::test]
+    async fn lost_swap_response_keeps_the_index_behind_the_alias() {
+        let scenario = Scenario {
+            swap_status: 500,
+            swap_applies: true,
+            ..SUCCESS
+        };
+        let (result, writes, _) = run(scenario, true).await;
+
+        assert!(result.is_err());
+        assert!(!writes.iter().any(|write| write.starts_with("DELETE")));
+    }
+
+    #[tokio::test]
+    async fn delete_old_removes_only_the_previous_index() {
+        let (result, writes, blocks) = run(SUCCESS, true).await;
+
+        result.unwrap();
+        assert_eq!(
+            writes,
+            [
+                "PUT /docs_v2",
+                "PUT /docs_v1/_settings",
+                "POST /_reindex",
+                "POST /_aliases",
+                "DELETE /docs_v1",
+            ]
+        );
+        assert_eq!(blocks, [true]);
+    }
+
+    #[tokio::test]
+    async fn previous_index_is_kept_writable_by_default() {
+        let (result, writes, blocks) = run(SUCCESS, false).await;
+
+        result.unwrap();
+        assert!(!writes.iter().any(|write| write.starts_with("DELETE")));
+        assert_eq!(blocks, [true, false]);
+    }
+
+    #[test]
+    fn versioned_names_round_trip() {
+        assert_eq!(versioned_name("docs", 1), "docs_v1");
+        assert_eq!(versioned_name("my_docs", 12), "my_docs_v12");
+        assert_eq!(parse_version("docs", "docs_v1"), Some(1));
+        assert_eq!(
+            parse_version("my_docs", &versioned_name("my_docs", 12)),
+            Some(12)
+        );
+    }
+
+    #[test]
+    fn other_index_names_have_no_version() {
+        for name in [
+            "docs",
+            "docs_v",
+            "docs_vx",
+            "docs_1",
+            "docs_v-1",
+            "other_v1",
+            "docs_v1_old",
+        ] {
+            assert_eq!(parse_version("docs", name), None, "{}", name);
+        }
+    }
+}
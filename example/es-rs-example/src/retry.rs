@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use elasticsearch::http::{StatusCode, headers::RETRY_AFTER, response::Response};
+use rand::Rng;
+
+/// Whether a request can safely be sent more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Reads without side effects, e.g. get, search, exists
+    Read,
+    /// Writes that leave the same state when repeated, e.g. put or delete by id
+    Idempotent,
+    /// Writes that must not be repeated, e.g. creating an index or indexing without an id
+    NonIdempotent,
+}
+
+impl Operation {
+    pub fn is_retryable(self) -> bool {
+        self != Operation::NonIdempotent
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter for the given attempt (1-based)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    /// Delay before retrying `response`, or `None` when it should not be retried.
+    /// A `Retry-After` header from the cluster takes precedence over the backoff
+    /// but is capped at `max_backoff`, so a busy cluster cannot stall us for hours.
+    pub fn delay_for(&self, response: &Response, attempt: u32) -> Option<Duration> {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok());
+        self.delay(response.status_code(), retry_after, attempt)
+    }
+
+    fn delay(
+        &self,
+        status: StatusCode,
+        retry_after: Option<&str>,
+        attempt: u32,
+    ) -> Option<Duration> {
+        if !is_transient_status(status) {
+            return None;
+        }
+
+        let retry_after = retry_after
+            .and_then(|value| value.trim().parse().ok())
+            .map(|seconds| Duration::from_secs(seconds).min(self.max_backoff));
+
+        Some(retry_after.unwrap_or_else(|| self.backoff(attempt)))
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 502 | 503 | 504)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> StatusCode {
+        StatusCode::from_u16(code).unwrap()
+    }
+
+    #[test]
+    fn retry_after_is_capped_at_max_backoff() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(
+            policy.delay(status(429), Some("3600"), 1),
+            Some(policy.max_backoff)
+        );
+        assert_eq!(
+            policy.delay(status(503), Some(" 2 "), 1),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn only_transient_statuses_are_retried() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.delay(status(400), Some("1"), 1), None);
+        assert_eq!(policy.delay(status(500), None, 1), None);
+        for code in [429, 502, 503, 504] {
+            let delay = policy.delay(status(code), None, 1).unwrap();
+            assert!(delay <= policy.initial_backoff);
+        }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max_backoff() {
+        let policy = RetryPolicy::default();
+
+        for attempt in 1..10 {
+            let ceiling = policy
+                .initial_backoff
+                .saturating_mul(1 << (attempt - 1))
+                .min(policy.max_backoff);
+            assert!(policy.backoff(attempt) <= ceiling);
+        }
+    }
+}
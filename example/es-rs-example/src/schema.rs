@@ -0,0 +1,635 @@
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::document::{DocChunk, EsDocument};
+
+/// Analyzers that ship with Elasticsearch and can be referenced without a
+/// definition, the language analyzers included. Plugins add more, so other
+/// names are only warned about.
+const BUILTIN_ANALYZERS: &[&str] = &[
+    "standard",
+    "simple",
+    "whitespace",
+    "stop",
+    "keyword",
+    "pattern",
+    "fingerprint",
+    "arabic",
+    "armenian",
+    "basque",
+    "bengali",
+    "brazilian",
+    "bulgarian",
+    "catalan",
+    "cjk",
+    "czech",
+    "danish",
+    "dutch",
+    "english",
+    "estonian",
+    "finnish",
+    "french",
+    "galician",
+    "german",
+    "greek",
+    "hindi",
+    "hungarian",
+    "indonesian",
+    "irish",
+    "italian",
+    "latvian",
+    "lithuanian",
+    "norwegian",
+    "persian",
+    "portuguese",
+    "romanian",
+    "russian",
+    "serbian",
+    "sorani",
+    "spanish",
+    "swedish",
+    "thai",
+    "turkish",
+];
+
+/// Vector similarity functions accepted by `dense_vector` fields
+const SIMILARITIES: &[&str] = &["cosine", "dot_product", "l2_norm", "max_inner_product"];
+
+/// Largest vector Elasticsearch can index
+pub const MAX_DIMS: u32 = 4096;
+
+/// Vector field of `DocChunk`, sized to the default embedding model
+pub const EMBEDDING_FIELD: &str = "embedding";
+pub const DEFAULT_EMBEDDING_DIMS: u32 = 1024;
+pub const DEFAULT_SIMILARITY: &str = "cosine";
+
+/// Body of a create index request. Every level keeps the keys this tool does
+/// not model in `extra` and sends them as they are, Elasticsearch validates them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexSchema {
+    #[serde(default)]
+    pub settings: IndexSettings,
+    #[serde(default)]
+    pub mappings: Mappings,
+    /// e.g. `aliases`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexSettings {
+    pub number_of_shards: u32,
+    pub number_of_replicas: u32,
+    #[serde(skip_serializing_if = "Analysis::is_empty")]
+    pub analysis: Analysis,
+    /// e.g. `refresh_interval` or `max_result_window`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        IndexSettings {
+            number_of_shards: 1,
+            number_of_replicas: 0,
+            analysis: Analysis::default(),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Analysis {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub analyzer: BTreeMap<String, Analyzer>,
+    /// Custom `tokenizer`, `filter`, `char_filter` and `normalizer` definitions
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl Analysis {
+    pub fn is_empty(&self) -> bool {
+        self.analyzer.is_empty() && self.extra.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Analyzer {
+    /// `custom` or the name of a built-in analyzer to configure
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub char_filter: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filter: Vec<String>,
+    /// Options of built-in analyzers, e.g. `stopwords`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Mappings {
+    #[serde(default)]
+    pub properties: BTreeMap<String, FieldMapping>,
+    /// e.g. `dynamic`, `_source` or `dynamic_templates`
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldMapping {
+    #[serde(rename = "type", default = "FieldType::object")]
+    pub field_type: FieldType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analyzer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_analyzer: Option<String>,
+    /// Date format, e.g. `strict_date_optional_time`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Number of dimensions of a `dense_vector` field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dims: Option<u32>,
+    /// Similarity used by kNN search on a `dense_vector` field, e.g. `cosine`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<String>,
+    /// Whether a `dense_vector` field is indexed for kNN search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<bool>,
+    /// Sub-fields of `object` and `nested` fields
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, FieldMapping>,
+    /// Options this tool does not model, e.g. `ignore_above`, `fields` or
+    /// `copy_to`, sent to Elasticsearch as they are
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl FieldMapping {
+    pub fn new(field_type: FieldType) -> Self {
+        FieldMapping {
+            field_type,
+            analyzer: None,
+            search_analyzer: None,
+            format: None,
+            dims: None,
+            similarity: None,
+            index: None,
+            properties: BTreeMap::new(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// An indexed `dense_vector` field, searchable with kNN
+    pub fn dense_vector(dims: u32, similarity: &str) -> Self {
+        FieldMapping {
+            dims: Some(dims),
+            similarity: Some(similarity.to_string()),
+            index: Some(true),
+            ..FieldMapping::new(FieldType::DenseVector)
+        }
+    }
+}
+
+/// Field data types. Types this tool does not know about are kept as `Other`
+/// so live mappings can still be read, but are rejected by `validate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum FieldType {
+    Text,
+    MatchOnlyText,
+    SearchAsYouType,
+    Completion,
+    Keyword,
+    ConstantKeyword,
+    Wildcard,
+    Date,
+    DateNanos,
+    Long,
+    Integer,
+    Short,
+    Byte,
+    UnsignedLong,
+    Double,
+    Float,
+    HalfFloat,
+    ScaledFloat,
+    Boolean,
+    Binary,
+    Ip,
+    GeoPoint,
+    GeoShape,
+    Object,
+    Nested,
+    Flattened,
+    DenseVector,
+    Other(String),
+}
+
+impl FieldType {
+    fn object() -> Self {
+        FieldType::Object
+    }
+
+    /// Types whose values go through an analyzer
+    fn is_analyzed(&self) -> bool {
+        matches!(
+            self,
+            FieldType::Text
+                | FieldType::MatchOnlyText
+                | FieldType::SearchAsYouType
+                | FieldType::Completion
+        )
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::MatchOnlyText => "match_only_text",
+            FieldType::SearchAsYouType => "search_as_you_type",
+            FieldType::Completion => "completion",
+            FieldType::Keyword => "keyword",
+            FieldType::ConstantKeyword => "constant_keyword",
+            FieldType::Wildcard => "wildcard",
+            FieldType::Date => "date",
+            FieldType::DateNanos => "date_nanos",
+            FieldType::Long => "long",
+            FieldType::Integer => "integer",
+            FieldType::Short => "short",
+            FieldType::Byte => "byte",
+            FieldType::UnsignedLong => "unsigned_long",
+            FieldType::Double => "double",
+            FieldType::Float => "float",
+            FieldType::HalfFloat => "half_float",
+            FieldType::ScaledFloat => "scaled_float",
+            FieldType::Boolean => "boolean",
+            FieldType::Binary => "binary",
+            FieldType::Ip => "ip",
+            FieldType::GeoPoint => "geo_point",
+            FieldType::GeoShape => "geo_shape",
+            FieldType::Object => "object",
+            FieldType::Nested => "nested",
+            FieldType::Flattened => "flattened",
+            FieldType::DenseVector => "dense_vector",
+            FieldType::Other(name) => name,
+        }
+    }
+}
+
+impl From<String> for FieldType {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "text" => FieldType::Text,
+            "match_only_text" => FieldType::MatchOnlyText,
+            "search_as_you_type" => FieldType::SearchAsYouType,
+            "completion" => FieldType::Completion,
+            "keyword" => FieldType::Keyword,
+            "constant_keyword" => FieldType::ConstantKeyword,
+            "wildcard" => FieldType::Wildcard,
+            "date" => FieldType::Date,
+            "date_nanos" => FieldType::DateNanos,
+            "long" => FieldType::Long,
+            "integer" => FieldType::Integer,
+            "short" => FieldType::Short,
+            "byte" => FieldType::Byte,
+            "unsigned_long" => FieldType::UnsignedLong,
+            "double" => FieldType::Double,
+            "float" => FieldType::Float,
+            "half_float" => FieldType::HalfFloat,
+            "scaled_float" => FieldType::ScaledFloat,
+            "boolean" => FieldType::Boolean,
+            "binary" => FieldType::Binary,
+            "ip" => FieldType::Ip,
+            "geo_point" => FieldType::GeoPoint,
+            "geo_shape" => FieldType::GeoShape,
+            "object" => FieldType::Object,
+            "nested" => FieldType::Nested,
+            "flattened" => FieldType::Flattened,
+            "dense_vector" => FieldType::DenseVector,
+            _ => FieldType::Other(name),
+        }
+    }
+}
+
+impl From<FieldType> for String {
+    fn from(field_type: FieldType) -> Self {
+        field_type.as_str().to_string()
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Every problem found in a schema, reported together
+#[derive(Debug)]
+pub struct SchemaError(pub Vec<String>);
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid index schema:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl IndexSchema {
+    /// Schema for the documentation chunks indexed by this tool, with
+    /// `embedding` sized to `dims`
+    pub fn docs_with_dims(dims: u32) -> Self {
+        let mut schema = Self::for_document::<DocChunk>();
+        schema.mappings.properties.insert(
+            EMBEDDING_FIELD.to_string(),
+            FieldMapping::dense_vector(dims, DEFAULT_SIMILARITY),
+        );
+        schema
+    }
+
+    /// Default settings with the mappings derived from `D`
+    pub fn for_document<D: EsDocument>() -> Self {
+        IndexSchema {
+            settings: IndexSettings::default(),
+            mappings: D::mappings(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    /// Load a schema from a JSON or TOML file, picked by extension
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read schema {}", path.display()))?;
+        let schema = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&raw).map_err(anyhow::Error::from),
+            _ => serde_json::from_str(&raw).map_err(anyhow::Error::from),
+        };
+
+        schema.with_context(|| format!("Failed to parse schema {}", path.display()))
+    }
+
+    /// Check the schema for mistakes Elasticsearch would reject, so nothing
+    /// half-valid is ever sent to the cluster
+    pub fn validate(&self) -> Result<(), SchemaError> {
+        let mut problems = Vec::new();
+
+        for (name, analyzer) in &self.settings.analysis.analyzer {
+            if BUILTIN_ANALYZERS.contains(&name.as_str()) {
+                problems.push(format!(
+                    "custom analyzer '{}' conflicts with the built-in analyzer of the same name",
+                    name
+                ));
+            }
+            if analyzer.kind == "custom" && analyzer.tokenizer.is_none() {
+                problems.push(format!("custom analyzer '{}' has no tokenizer", name));
+            }
+        }
+
+        for (name, field) in &self.mappings.properties {
+            self.validate_field(name, field, &mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaError(problems))
+        }
+    }
+
+    fn validate_field(&self, path: &str, field: &FieldMapping, problems: &mut Vec<String>) {
+        if let FieldType::Other(name) = &field.field_type {
+            problems.push(format!("field '{}' has unknown type '{}'", path, name));
+        }
+
+        let analyzers = [
+            ("analyzer", &field.analyzer),
+            ("search_analyzer", &field.search_analyzer),
+        ];
+        for (setting, analyzer) in analyzers {
+            if analyzer.is_some() && !field.field_type.is_analyzed() {
+                problems.push(format!(
+                    "field '{}' sets {} but is of type '{}', only text fields are analyzed",
+                    path, setting, field.field_type
+                ));
+            }
+        }
+        if field.search_analyzer.is_some() && field.analyzer.is_none() {
+            problems.push(format!(
+                "field '{}' sets search_analyzer without an analyzer",
+                path
+            ));
+        }
+
+        let is_date = matches!(field.field_type, FieldType::Date | FieldType::DateNanos);
+        if field.format.is_some() && !is_date {
+            problems.push(format!(
+                "field '{}' sets a format but is of type '{}'",
+                path, field.field_type
+            ));
+        }
+
+        if field.field_type == FieldType::DenseVector {
+            match field.dims {
+                None => problems.push(format!("dense_vector field '{}' has no dims", path)),
+                Some(dims) if dims == 0 || dims > MAX_DIMS => problems.push(format!(
+                    "field '{}' has {} dims, expected 1 to {}",
+                    path, dims, MAX_DIMS
+                )),
+                Some(_) => {}
+            }
+            if let Some(similarity) = &field.similarity
+                && !SIMILARITIES.contains(&similarity.as_str())
+            {
+                problems.push(format!(
+                    "field '{}' has unknown similarity '{}', expected one of {}",
+                    path,
+                    similarity,
+                    SIMILARITIES.join(", ")
+                ));
+            }
+        } else if field.dims.is_some() || field.similarity.is_some() {
+            problems.push(format!(
+                "field '{}' sets dims or similarity but is of type '{}'",
+                path, field.field_type
+            ));
+        }
+
+        let is_object = matches!(field.field_type, FieldType::Object | FieldType::Nested);
+        if !is_object && !field.properties.is_empty() {
+            problems.push(format!(
+                "field '{}' has sub-properties but is of type '{}'",
+                path, field.field_type
+            ));
+        }
+        for (name, child) in &field.properties {
+            self.validate_field(&format!("{}.{}", path, name), child, problems);
+        }
+    }
+
+    /// Likely mistakes Elasticsearch may still accept, such as an analyzer
+    /// that is neither built in nor defined, which a plugin could provide
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (name, field) in &self.mappings.properties {
+            self.check_analyzers(name, field, &mut warnings);
+        }
+        warnings
+    }
+
+    fn check_analyzers(&self, path: &str, field: &FieldMapping, warnings: &mut Vec<String>) {
+        for analyzer in [&field.analyzer, &field.search_analyzer]
+            .into_iter()
+            .flatten()
+        {
+            if !self.is_known_analyzer(analyzer) {
+                warnings.push(format!(
+                    "field '{}' references analyzer '{}', which is neither built in nor defined in the settings",
+                    path, analyzer
+                ));
+            }
+        }
+        for (name, child) in &field.properties {
+            self.check_analyzers(&format!("{}.{}", path, name), child, warnings);
+        }
+    }
+
+    fn is_known_analyzer(&self, name: &str) -> bool {
+        BUILTIN_ANALYZERS.contains(&name) || self.settings.analysis.analyzer.contains_key(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unknown_field_options_are_passed_through() {
+        let file = json!({
+            "mappings": {
+                "properties": {
+                    "title": {
+                        "type": "text",
+                        "fields": { "raw": { "type": "keyword", "ignore_above": 256 } },
+                        "copy_to": "all"
+                    },
+                    "status": { "type": "keyword", "null_value": "none" }
+                }
+            }
+        });
+        let schema: IndexSchema = serde_json::from_value(file.clone()).unwrap();
+        schema.validate().unwrap();
+
+        let body = serde_json::to_value(&schema).unwrap();
+        assert_eq!(body["mappings"], file["mappings"]);
+    }
+
+    #[test]
+    fn unknown_keys_are_passed_through_at_every_level() {
+        let file = json!({
+            "settings": {
+                "number_of_shards": 1,
+                "number_of_replicas": 0,
+                "refresh_interval": "30s",
+                "analysis": {
+                    "analyzer": {
+                        "code": { "type": "custom", "tokenizer": "code_tokenizer", "filter": ["lowercase"] },
+                        "english_stop": { "type": "standard", "stopwords": "_english_" }
+                    },
+                    "tokenizer": { "code_tokenizer": { "type": "pattern", "pattern": "\\W+" } },
+                    "normalizer": { "folded": { "type": "custom", "filter": ["lowercase"] } }
+                }
+            },
+            "mappings": {
+                "dynamic": "strict",
+                "_source": { "excludes": ["embedding"] },
+                "dynamic_templates": [{ "strings": { "match_mapping_type": "string", "mapping": { "type": "keyword" } } }],
+                "properties": { "body": { "type": "text", "analyzer": "code" } }
+            },
+            "aliases": { "docs": {} }
+        });
+        let schema: IndexSchema = serde_json::from_value(file.clone()).unwrap();
+        schema.validate().unwrap();
+
+        assert_eq!(serde_json::to_value(&schema).unwrap(), file);
+    }
+
+    #[test]
+    fn less_common_field_types_are_known() {
+        let types = [
+            "ip",
+            "short",
+            "byte",
+            "scaled_float",
+            "geo_point",
+            "flattened",
+            "wildcard",
+            "match_only_text",
+        ];
+        for name in types {
+            let field_type = FieldType::from(name.to_string());
+            assert!(!matches!(field_type, FieldType::Other(_)), "{}", name);
+            assert_eq!(field_type.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn docs_schema_is_valid() {
+        IndexSchema::docs_with_dims(DEFAULT_EMBEDDING_DIMS)
+            .validate()
+            .unwrap();
+        let schema = IndexSchema::docs_with_dims(384);
+        assert_eq!(schema.mappings.properties[EMBEDDING_FIELD].dims, Some(384));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let schema: IndexSchema = serde_json::from_value(json!({
+            "mappings": {
+                "properties": {
+                    "count": { "type": "long", "analyzer": "missing" },
+                    "vector": { "type": "dense_vector" }
+                }
+            }
+        }))
+        .unwrap();
+
+        let SchemaError(problems) = schema.validate().unwrap_err();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+    }
+
+    #[test]
+    fn unknown_analyzers_are_warnings() {
+        let schema: IndexSchema = serde_json::from_value(json!({
+            "mappings": {
+                "properties": {
+                    "title": { "type": "text", "analyzer": "russian" },
+                    "body": {
+                        "type": "object",
+                        "properties": {
+                            "ja": { "type": "text", "analyzer": "kuromoji" }
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        schema.validate().unwrap();
+        let warnings = schema.warnings();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("'body.ja'"), "{}", warnings[0]);
+        assert!(warnings[0].contains("'kuromoji'"), "{}", warnings[0]);
+    }
+}
@@ -0,0 +1,703 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::SearchParts;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{
+    client::EsClient,
+    error::{self, InvalidRequest},
+    retry::Operation,
+    schema::EMBEDDING_FIELD,
+    version,
+};
+
+/// Fields the query runs against, a match in the title counts three times as much
+const SEARCH_FIELDS: &[&str] = &["title^3", "content"];
+
+/// Characters per highlighted fragment of `content`
+const FRAGMENT_SIZE: u64 = 150;
+const MAX_FRAGMENTS: u64 = 3;
+
+/// Number of tag buckets returned as facets
+const TAG_FACETS: u64 = 20;
+const DEFAULT_DATE_INTERVAL: &str = "month";
+
+/// Candidates considered per shard for every kNN hit returned
+const CANDIDATES_PER_HIT: usize = 10;
+const MAX_CANDIDATES: usize = 10_000;
+
+/// Hits fetched from each retriever before fusing, per hit returned
+const WINDOW_PER_HIT: usize = 5;
+
+/// Restrictions shared by every kind of search
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    /// Only search this library
+    pub library: Option<String>,
+    /// Version to search, the latest one of each library when `None`
+    pub version: Option<String>,
+    /// Only return chunks carrying all of these tags
+    pub tags: Vec<String>,
+    /// Date range, inclusive. Anything Elasticsearch accepts in a range
+    /// query works, e.g. `2024-01-01` or `now-30d`.
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+impl Filters {
+    /// Filter clauses for a bool query or a kNN pre-filter
+    async fn clauses(&self, client: &EsClient, index_name: &str) -> Result<Vec<Value>> {
+        let version = version::filter(
+            client,
+            index_name,
+            self.library.as_deref(),
+            self.version.as_deref(),
+        )
+        .await?;
+
+        let mut clauses = vec![version];
+        for tag in &self.tags {
+            clauses.push(json!({ "term": { "tags": tag } }));
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let mut range = json!({});
+            if let Some(since) = &self.since {
+                range["gte"] = json!(since);
+            }
+            if let Some(until) = &self.until {
+                range["lte"] = json!(until);
+            }
+            clauses.push(json!({ "range": { "date": range } }));
+        }
+
+        Ok(clauses)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub filters: Filters,
+    /// Bucket size of the date facet, `month` by default
+    pub date_interval: Option<String>,
+    pub from: i64,
+    pub size: i64,
+    /// Sort values of the last hit of the previous page, replaces `from` for
+    /// result sets deeper than the 10 000 hits `from` can reach
+    pub search_after: Option<Vec<Value>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct KnnQuery {
+    pub vector: Vec<f32>,
+    /// Number of nearest chunks to return
+    pub k: usize,
+    /// Candidates considered per shard, `k * 10` by default. More is slower but more accurate.
+    pub num_candidates: Option<usize>,
+    /// Applied before the nearest neighbours are picked, so `k` hits come back
+    /// whenever enough chunks match
+    pub filters: Filters,
+}
+
+/// Lexical and kNN search over the same filters, merged with reciprocal rank fusion
+#[derive(Debug, Clone)]
+pub struct HybridQuery {
+    pub text: String,
+    pub vector: Vec<f32>,
+    pub filters: Filters,
+    pub size: usize,
+    /// Hits taken from each retriever before fusing, `size * 5` by default
+    pub window: Option<usize>,
+    pub rrf: RrfParams,
+}
+
+/// A hit at rank `r` (1-based) of a retriever scores `weight / (rank_constant + r)`,
+/// scores of the same chunk are summed over retrievers
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RrfParams {
+    pub rank_constant: f64,
+    pub lexical_weight: f64,
+    pub vector_weight: f64,
+}
+
+impl Default for RrfParams {
+    fn default() -> Self {
+        RrfParams {
+            rank_constant: 60.0,
+            lexical_weight: 1.0,
+            vector_weight: 1.0,
+        }
+    }
+}
+
+impl RrfParams {
+    /// A rank constant of zero or less divides by zero or flips the order of
+    /// the ranks, a negative weight pushes the hits of its retriever down
+    pub fn validate(&self) -> Result<()> {
+        if !self.rank_constant.is_finite() || self.rank_constant <= 0.0 {
+            bail!(InvalidRequest(format!(
+                "rank_constant must be a positive number, got {}",
+                self.rank_constant
+            )));
+        }
+        for (name, weight) in [
+            ("lexical_weight", self.lexical_weight),
+            ("vector_weight", self.vector_weight),
+        ] {
+            if !weight.is_finite() || weight < 0.0 {
+                bail!(InvalidRequest(format!(
+                    "{} must be zero or more, got {}",
+                    name, weight
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: Option<f64>,
+    pub title: String,
+    pub repo: String,
+    pub version: Option<String>,
+    /// Ref and commit the chunk was read at, `None` for a working tree
+    pub git_ref: Option<String>,
+    pub commit: Option<String>,
+    pub path: String,
+    pub heading_path: Vec<String>,
+    pub content: String,
+    /// Matching fragments of the content, matches wrapped in `**`
+    pub highlights: Vec<String>,
+    /// Sort values to pass as `search_after` to get the next page
+    pub sort: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Bucket {
+    pub key: String,
+    pub count: u64,
+}
+
+/// Counts over every hit, not just the current page, for drilling down
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Facets {
+    pub tags: Vec<Bucket>,
+    pub dates: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResults {
+    /// Matching chunks, the larger total of the two searches for hybrid ones
+    pub total: u64,
+    pub hits: Vec<SearchHit>,
+    pub facets: Facets,
+    /// `search_after` for the following page, `None` on the last page
+    pub next_page: Option<Vec<Value>>,
+}
+
+/// Search one version of each library, or only the filtered library, ranked
+/// by BM25 over title and content
+pub async fn search(
+    client: &EsClient,
+    index_name: &str,
+    query: &SearchQuery,
+) -> Result<SearchResults> {
+    if query.search_after.is_some() && query.from > 0 {
+        bail!(InvalidRequest(
+            "from and search_after cannot be combined".to_string()
+        ));
+    }
+
+    let filters = query.filters.clauses(client, index_name).await?;
+    lexical(client, index_name, query, &filters).await
+}
+
+async fn lexical(
+    client: &EsClient,
+    index_name: &str,
+    query: &SearchQuery,
+    filters: &[Value],
+) -> Result<SearchResults> {
+    let date_interval = query
+        .date_interval
+        .as_deref()
+        .unwrap_or(DEFAULT_DATE_INTERVAL);
+    let mut body = json!({
+        "query": {
+            "bool": {
+                "must": {
+                    "multi_match": {
+                        "query": query.text,
+                        "fields": SEARCH_FIELDS
+                    }
+                },
+                "filter": filters
+            }
+        },
+        // chunk_id breaks ties between equal scores so pages never overlap
+        "sort": [{ "_score": "desc" }, { "chunk_id": "asc" }],
+        "_source": { "excludes": [EMBEDDING_FIELD] },
+        "highlight": {
+            "pre_tags": ["**"],
+            "post_tags": ["**"],
+            "fields": {
+                "content": {
+                    "fragment_size": FRAGMENT_SIZE,
+                    "number_of_fragments": MAX_FRAGMENTS
+                }
+            }
+        },
+        "aggs": {
+            "tags": { "terms": { "field": "tags", "size": TAG_FACETS } },
+            "dates": {
+                "date_histogram": {
+                    "field": "date",
+                    "calendar_interval": date_interval,
+                    "format": "yyyy-MM-dd",
+                    "min_doc_count": 1
+                }
+            }
+        }
+    });
+    if let Some(search_after) = &query.search_after {
+        body["search_after"] = json!(search_after);
+    }
+
+    let body = send(client, index_name, &body, query.from, query.size).await?;
+    let mut results = parse_results(&body);
+    if results.hits.len() as i64 >= query.size {
+        results.next_page = results.hits.last().map(|hit| hit.sort.clone());
+    }
+    Ok(results)
+}
+
+/// The `query.k` chunks whose embedding is closest to `query.vector`
+pub async fn knn(client: &EsClient, index_name: &str, query: &KnnQuery) -> Result<SearchResults> {
+    check_k(query.k)?;
+    let filters = query.filters.clauses(client, index_name).await?;
+    nearest(client, index_name, query, &filters).await
+}
+
+/// Checked before anything is sent, Elasticsearch rejects the same bounds
+fn check_k(k: usize) -> Result<()> {
+    if k == 0 {
+        bail!(InvalidRequest("k must be at least 1".to_string()));
+    }
+    if k > MAX_CANDIDATES {
+        bail!(InvalidRequest(format!(
+            "k must be at most {}, got {}",
+            MAX_CANDIDATES, k
+        )));
+    }
+    Ok(())
+}
+
+async fn nearest(
+    client: &EsClient,
+    index_name: &str,
+    query: &KnnQuery,
+    filters: &[Value],
+) -> Result<SearchResults> {
+    let num_candidates = query
+        .num_candidates
+        .unwrap_or(query.k.saturating_mul(CANDIDATES_PER_HIT))
+        .clamp(query.k, MAX_CANDIDATES);
+    let body = json!({
+        "knn": {
+            "field": EMBEDDING_FIELD,
+            "query_vector": query.vector,
+            "k": query.k,
+            "num_candidates": num_candidates,
+            "filter": filters
+        },
+        "_source": { "excludes": [EMBEDDING_FIELD] }
+    });
+
+    let body = send(client, index_name, &body, 0, query.k as i64).await?;
+    Ok(parse_results(&body))
+}
+
+/// Run the lexical and the kNN query side by side and fuse their rankings
+/// with RRF. Fusing happens here rather than in Elasticsearch, whose RRF
+/// retriever needs a paid license.
+pub async fn hybrid(
+    client: &EsClient,
+    index_name: &str,
+    query: &HybridQuery,
+) -> Result<SearchResults> {
+    if query.size == 0 {
+        bail!(InvalidRequest("size must be at least 1".to_string()));
+    }
+    query.rrf.validate()?;
+    let window = query
+        .window
+        .unwrap_or(
+            query
+                .size
+                .saturating_mul(WINDOW_PER_HIT)
+                .min(MAX_CANDIDATES),
+        )
+        .max(query.size);
+    check_k(window)?;
+
+    let filters = query.filters.clauses(client, index_name).await?;
+    let lexical_query = SearchQuery {
+        text: query.text.clone(),
+        filters: query.filters.clone(),
+        date_interval: None,
+        from: 0,
+        size: window as i64,
+        search_after: None,
+    };
+    let knn_query = KnnQuery {
+        vector: query.vector.clone(),
+        k: window,
+        num_candidates: None,
+        filters: query.filters.clone(),
+    };
+    let (by_text, by_vector) = tokio::try_join!(
+        lexical(client, index_name, &lexical_query, &filters),
+        nearest(client, index_name, &knn_query, &filters),
+    )?;
+
+    // Every chunk either search matched is a result, not only the fused page
+    let total = by_text.total.max(by_vector.total);
+    let mut hits = fuse(
+        [
+            (query.rrf.lexical_weight, by_text.hits),
+            (query.rrf.vector_weight, by_vector.hits),
+        ],
+        query.rrf.rank_constant,
+    );
+    hits.truncate(query.size);
+
+    Ok(SearchResults {
+        total,
+        hits,
+        facets: by_text.facets,
+        next_page: None,
+    })
+}
+
+/// Reciprocal rank fusion of weighted rankings, best first. A chunk found by
+/// several retrievers keeps the first copy, which carries the highlights of
+/// the lexical query.
+fn fuse<const N: usize>(
+    rankings: [(f64, Vec<SearchHit>); N],
+    rank_constant: f64,
+) -> Vec<SearchHit> {
+    let mut fused: Vec<SearchHit> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut scores: Vec<f64> = Vec::new();
+
+    for (weight, hits) in rankings {
+        for (rank, hit) in hits.into_iter().enumerate() {
+            let score = weight / (rank_constant + rank as f64 + 1.0);
+            match positions.get(&hit.id) {
+                Some(&position) => scores[position] += score,
+                None => {
+                    positions.insert(hit.id.clone(), fused.len());
+                    fused.push(hit);
+                    scores.push(score);
+                }
+            }
+        }
+    }
+
+    let mut fused: Vec<(f64, SearchHit)> = scores.into_iter().zip(fused).collect();
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused
+        .into_iter()
+        .map(|(score, hit)| SearchHit {
+            score: Some(score),
+            sort: Vec::new(),
+            ..hit
+        })
+        .collect()
+}
+
+async fn send(
+    client: &EsClient,
+    index_name: &str,
+    body: &Value,
+    from: i64,
+    size: i64,
+) -> Result<Value> {
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.search(SearchParts::Index(&[index_name]))
+                .from(from)
+                .size(size)
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let response = error::check(response).await.context("Search failed")?;
+
+    Ok(response.json().await?)
+}
+
+fn parse_results(body: &Value) -> SearchResults {
+    let hits = body["hits"]["hits"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(parse_hit)
+        .collect();
+
+    let aggregations = &body["aggregations"];
+    SearchResults {
+        total: body["hits"]["total"]["value"].as_u64().unwrap_or_default(),
+        hits,
+        facets: Facets {
+            tags: parse_buckets(&aggregations["tags"], "key"),
+            dates: parse_buckets(&aggregations["dates"], "key_as_string"),
+        },
+        next_page: None,
+    }
+}
+
+fn parse_buckets(aggregation: &Value, key: &str) -> Vec<Bucket> {
+    aggregation["buckets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|bucket| {
+            Some(Bucket {
+                key: bucket[key].as_str()?.to_string(),
+                count: bucket["doc_count"].as_u64()?,
+            })
+        })
+        .collect()
+}
+
+fn parse_hit(hit: &Value) -> SearchHit {
+    let source = &hit["_source"];
+    let strings = |value: &Value| -> Vec<String> {
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect()
+    };
+    let string = |value: &Value| value.as_str().unwrap_or_default().to_string();
+
+    SearchHit {
+        id: string(&hit["_id"]),
+        score: hit["_score"].as_f64(),
+        title: string(&source["title"]),
+        repo: string(&source["repo"]),
+        version: source["version"].as_str().map(str::to_string),
+        git_ref: source["git_ref"].as_str().map(str::to_string),
+        commit: source["commit"].as_str().map(str::to_string),
+        path: string(&source["path"]),
+        heading_path: strings(&source["heading_path"]),
+        content: string(&source["content"]),
+        highlights: strings(&hit["highlight"]["content"]),
+        sort: hit["sort"].as_array().cloned().unwrap_or_default(),
+    }
+}
+
+/// Print a page of results, numbering hits from `from + 1`
+pub fn print(results: &SearchResults, from: i64) {
+    println!("Found {} hits", results.total);
+
+    for (position, hit) in results.hits.iter().enumerate() {
+        println!();
+        println!(
+            "{}. {} (score {})",
+            from + position as i64 + 1,
+            hit.title,
+            hit.score
+                .map_or_else(|| "-".to_string(), |score| format!("{:.3}", score))
+        );
+        println!(
+            "   {}@{} {}",
+            hit.repo,
+            hit.version.as_deref().unwrap_or("-"),
+            hit.path
+        );
+        if !hit.heading_path.is_empty() {
+            println!("   {}", hit.heading_path.join(" > "));
+        }
+        for fragment in &hit.highlights {
+            println!("   ... {} ...", fragment.replace('\n', " "));
+        }
+    }
+
+    print_facet("Tags", &results.facets.tags);
+    print_facet("Dates", &results.facets.dates);
+
+    if let Some(sort) = &results.next_page {
+        println!();
+        println!("Next page: --search-after '{}'", json!(sort));
+    }
+}
+
+fn print_facet(name: &str, buckets: &[Bucket]) {
+    if buckets.is_empty() {
+        return;
+    }
+    let counts: Vec<String> = buckets
+        .iter()
+        .map(|bucket| format!("{} ({})", bucket.key, bucket.count))
+        .collect();
+    println!();
+    println!("{}: {}", name, counts.join(", "));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::pool::tests::{closed_node, config, mock_server};
+
+    pub(crate) fn hit(id: &str, content: &str) -> SearchHit {
+        SearchHit {
+            id: id.to_string(),
+            score: None,
+            title: id.to_string(),
+            repo: "docs".to_string(),
+            version: None,
+            git_ref: None,
+            commit: None,
+            path: format!("{}.md", id),
+            heading_path: Vec::new(),
+            content: content.to_string(),
+            highlights: Vec::new(),
+            sort: Vec::new(),
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|hit| hit.id.as_str()).collect()
+    }
+
+    #[test]
+    fn fuse_ranks_hits_found_by_both_retrievers_first() {
+        let lexical = vec![hit("a", ""), hit("b", ""), hit("c", "")];
+        let vector = vec![hit("c", ""), hit("d", ""), hit("b", "")];
+        let fused = fuse([(1.0, lexical), (1.0, vector)], 60.0);
+
+        assert_eq!(ids(&fused), ["c", "b", "a", "d"]);
+        let best = 1.0 / 61.0 + 1.0 / 63.0;
+        assert!((fused[0].score.unwrap() - best).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rrf_params_need_a_positive_constant_and_non_negative_weights() {
+        assert!(RrfParams::default().validate().is_ok());
+        let zero_weights = RrfParams {
+            lexical_weight: 0.0,
+            vector_weight: 0.0,
+            ..RrfParams::default()
+        };
+        assert!(zero_weights.validate().is_ok());
+
+        for invalid in [
+            RrfParams {
+                rank_constant: 0.0,
+                ..RrfParams::default()
+            },
+            RrfParams {
+                rank_constant: -1.0,
+                ..RrfParams::default()
+            },
+            RrfParams {
+                rank_constant: f64::NAN,
+                ..RrfParams::default()
+            },
+            RrfParams {
+                lexical_weight: -1.0,
+                ..RrfParams::default()
+            },
+            RrfParams {
+                vector_weight: f64::INFINITY,
+                ..RrfParams::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn fuse_weights_rankings() {
+        let lexical = vec![hit("a", ""), hit("b", "")];
+        let vector = vec![hit("b", ""), hit("a", "")];
+
+        let fused = fuse([(1.0, lexical.clone()), (3.0, vector.clone())], 60.0);
+        assert_eq!(ids(&fused), ["b", "a"]);
+        let fused = fuse([(3.0, lexical), (1.0, vector)], 60.0);
+        assert_eq!(ids(&fused), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn hybrid_total_counts_every_match_not_the_fused_page() {
+        let (url, _) = mock_server(|request| {
+            let body = request.json();
+            let (total, ids) = if body["aggs"]["libraries"].is_object() {
+                let libraries = json!([{ "key": "tokio", "versions": { "buckets": [] } }]);
+                let body = json!({ "aggregations": { "libraries": { "buckets": libraries } } });
+                return (200, body.to_string());
+            } else if body["knn"].is_object() {
+                (3, ["b", "c", "d"])
+            } else {
+                (42, ["a", "b", "c"])
+            };
+            let hits: Vec<Value> = ids
+                .iter()
+                .map(|id| json!({ "_id": id, "_score": 1.0, "_source": { "title": id } }))
+                .collect();
+            let body = json!({ "hits": { "total": { "value": total }, "hits": hits } });
+            (200, body.to_string())
+        })
+        .await;
+        let client = EsClient::connect(&config(&[&url])).await.unwrap();
+        let query = HybridQuery {
+            text: "spawn".to_string(),
+            vector: vec![1.0],
+            filters: Filters::default(),
+            size: 2,
+            window: None,
+            rrf: RrfParams::default(),
+        };
+
+        let results = hybrid(&client, "docs", &query).await.unwrap();
+        assert_eq!(results.total, 42);
+        assert_eq!(ids(&results.hits), ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn knn_rejects_k_above_the_candidate_limit() {
+        let client = EsClient::connect(&config(&[&closed_node().await]))
+            .await
+            .unwrap();
+        let query = KnnQuery {
+            vector: vec![1.0],
+            k: MAX_CANDIDATES + 1,
+            num_candidates: None,
+            filters: Filters::default(),
+        };
+
+        let err = knn(&client, "docs", &query).await.unwrap_err();
+        assert!(err.to_string().contains("k must be at most"));
+    }
+
+    #[test]
+    fn fuse_keeps_the_first_copy_of_a_hit() {
+        let mut lexical = hit("a", "");
+        lexical.highlights = vec!["**match**".to_string()];
+        lexical.sort = vec![Value::from(1)];
+        let fused = fuse([(1.0, vec![lexical]), (1.0, vec![hit("a", "")])], 60.0);
+
+        assert_eq!(fused.len(), 1);
+        assert_eq!(fused[0].highlights, ["**match**"]);
+        assert!(fused[0].sort.is_empty());
+    }
+}
@@ -0,0 +1,655 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    bulk::BulkOptions,
+    client::EsClient,
+    context::{self, ContextQuery, DEFAULT_MAX_TOKENS},
+    embed::{self, Embedder},
+    error,
+    ingest::{self, IngestOptions, markdown::SplitOptions},
+    metadata::{self, MetadataStore},
+    search::{self, Filters, HybridQuery, KnnQuery, RrfParams, SearchQuery, SearchResults},
+    tokenizer::Tokenizer,
+    version,
+};
+
+#[derive(Clone)]
+struct AppState {
+    client: EsClient,
+    index: String,
+    embedder: Option<Arc<dyn Embedder>>,
+    tokenizer: Arc<dyn Tokenizer>,
+    store: MetadataStore,
+    /// Directory every ingested path must be in, ingestion is disabled when `None`
+    ingest_root: Option<PathBuf>,
+    /// Repositories being ingested, each can only be ingested once at a time
+    ingesting: Arc<Mutex<HashSet<String>>>,
+}
+
+impl AppState {
+    /// Mark `repo` as being ingested until the guard is dropped,
+    /// `None` when an ingestion of it is already running
+    fn start_ingest(&self, repo: &str) -> Option<IngestGuard> {
+        let mut ingesting = self.ingesting.lock().unwrap();
+        if !ingesting.insert(repo.to_string()) {
+            return None;
+        }
+        Some(IngestGuard {
+            ingesting: self.ingesting.clone(),
+            repo: repo.to_string(),
+        })
+    }
+
+    fn is_ingesting(&self, repo: &str) -> bool {
+        self.ingesting.lock().unwrap().contains(repo)
+    }
+}
+
+struct IngestGuard {
+    ingesting: Arc<Mutex<HashSet<String>>>,
+    repo: String,
+}
+
+impl Drop for IngestGuard {
+    fn drop(&mut self) {
+        self.ingesting.lock().unwrap().remove(&self.repo);
+    }
+}
+
+/// An error answered as `{"error": "..."}` with the status `error::http_status` picks
+struct ApiError(StatusCode, anyhow::Error);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        let status = StatusCode::from_u16(error::http_status(&err))
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        ApiError(status, err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let ApiError(status, err) = self;
+        if status.is_server_error() {
+            eprintln!("{} {:#}", status, err);
+        }
+        (status, Json(json!({ "error": format!("{:#}", err) }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+/// Serve the REST API for `index` on `addr` until Ctrl-C. Only directories
+/// below `ingest_root` can be ingested, none when it is `None`.
+pub async fn serve(
+    client: EsClient,
+    index: String,
+    embedder: Option<Arc<dyn Embedder>>,
+    tokenizer: Arc<dyn Tokenizer>,
+    store: MetadataStore,
+    ingest_root: Option<PathBuf>,
+    addr: SocketAddr,
+) -> Result<()> {
+    let ingest_root = ingest_root
+        .map(|root| {
+            root.canonicalize()
+                .with_context(|| format!("Failed to resolve {}", root.display()))
+        })
+        .transpose()?;
+    let state = AppState {
+        client,
+        index,
+        embedder,
+        tokenizer,
+        store,
+        ingest_root,
+        ingesting: Arc::default(),
+    };
+    let app = router(state);
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Listening on http://{}", addr);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .context("Server failed")
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/api/repositories", get(list_repositories).post(ingest))
+        .route("/api/repositories/{name}", delete(delete_repository))
+        .route("/api/search", get(search_docs))
+        .route("/api/context", get(context_docs))
+        .with_state(state)
+}
+
+#[derive(Serialize)]
+struct Repository {
+    name: String,
+    /// Newest first, empty for repositories ingested without a version
+    versions: Vec<String>,
+    /// Status and counts, missing for chunks indexed without the metadata store
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<metadata::Repository>,
+}
+
+/// `GET /api/repositories`, indexed ones and ones still being ingested
+async fn list_repositories(State(state): State<AppState>) -> ApiResult<Json<Vec<Repository>>> {
+    let libraries = version::list(&state.client, &state.index, None).await?;
+    let mut repositories: BTreeMap<String, Repository> = libraries
+        .into_iter()
+        .map(|(name, versions)| {
+            let repository = Repository {
+                name: name.clone(),
+                versions,
+                metadata: None,
+            };
+            (name, repository)
+        })
+        .collect();
+    for stored in state.store.list().await? {
+        let name = stored.name.clone();
+        repositories
+            .entry(name.clone())
+            .or_insert_with(|| Repository {
+                name,
+                versions: Vec::new(),
+                metadata: None,
+            })
+            .metadata = Some(stored);
+    }
+    Ok(Json(repositories.into_values().collect()))
+}
+
+#[derive(Deserialize)]
+struct IngestRequest {
+    /// Directory or git repository on the server, relative to the ingest root
+    /// or absolute but inside it
+    path: PathBuf,
+    /// Defaults to the name of the directory
+    name: Option<String>,
+    /// Read the files at this ref instead of the working tree
+    git_ref: Option<String>,
+    version: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// `POST /api/repositories`, ingestion runs in the background and its
+/// progress and errors go to the server log
+async fn ingest(
+    State(state): State<AppState>,
+    Json(request): Json<IngestRequest>,
+) -> ApiResult<(StatusCode, Json<serde_json::Value>)> {
+    let root = ingest_path(&state, &request.path)?;
+    let repo = match request.name {
+        Some(name) => name,
+        None => ingest::repo_name(&root)?,
+    };
+    let Some(guard) = state.start_ingest(&repo) else {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("'{}' is already being ingested", repo),
+        ));
+    };
+    let git_ref = request.git_ref;
+    let options = IngestOptions {
+        root,
+        index: state.index.clone(),
+        repo: repo.clone(),
+        version: request.version,
+        tags: request.tags,
+        split: SplitOptions::default(),
+        bulk: BulkOptions::default(),
+        embedder: state.embedder.clone(),
+        tokenizer: state.tokenizer.clone(),
+    };
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        let result = ingest::ingest_repo(
+            &state.client,
+            Some(&state.store),
+            &options,
+            git_ref.as_deref(),
+        )
+        .await;
+        if let Err(err) = result {
+            eprintln!("Ingesting '{}' failed: {:#}", options.repo, err);
+        }
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "name": repo, "status": "in_progress" })),
+    ))
+}
+
+/// `path` resolved inside the ingest root, symlinks and `..` included
+fn ingest_path(state: &AppState, path: &std::path::Path) -> ApiResult<PathBuf> {
+    let Some(ingest_root) = &state.ingest_root else {
+        return Err(ApiError(
+            StatusCode::FORBIDDEN,
+            anyhow::anyhow!("Ingestion is disabled, start the server with --ingest-root"),
+        ));
+    };
+    let resolved = ingest_root.join(path).canonicalize().ok();
+    let Some(resolved) = resolved.filter(|resolved| resolved.is_dir()) else {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("{} is not a directory", path.display()),
+        ));
+    };
+    if !resolved.starts_with(ingest_root) {
+        return Err(ApiError(
+            StatusCode::FORBIDDEN,
+            anyhow::anyhow!("{} is outside the ingest root", path.display()),
+        ));
+    }
+    Ok(resolved)
+}
+
+/// `DELETE /api/repositories/{name}`, every version of the repository
+async fn delete_repository(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<serde_json::Value>> {
+    if state.is_ingesting(&name) {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("'{}' is being ingested, delete it once that is done", name),
+        ));
+    }
+    let deleted = ingest::delete_repo(&state.client, &state.index, &name).await?;
+    let stored = state.store.delete(&name).await?;
+    if deleted == 0 && !stored {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Repository '{}' is not indexed", name),
+        ));
+    }
+    Ok(Json(json!({ "name": name, "deleted": deleted })))
+}
+
+/// Hybrid when an embedder is configured, text otherwise
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SearchMode {
+    /// Full-text only
+    Text,
+    /// kNN only
+    Vector,
+    /// Both, fused with RRF
+    Hybrid,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    q: String,
+    mode: Option<SearchMode>,
+    #[serde(default = "default_size")]
+    size: usize,
+    /// Offset of the first hit, text mode only
+    #[serde(default)]
+    from: usize,
+}
+
+/// Query parameters shared by search and context, read next to the others
+#[derive(Deserialize)]
+struct FilterParams {
+    library: Option<String>,
+    version: Option<String>,
+    /// Comma separated, chunks must carry all of them
+    tags: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl From<FilterParams> for Filters {
+    fn from(params: FilterParams) -> Self {
+        Filters {
+            library: params.library,
+            version: params.version,
+            tags: params
+                .tags
+                .iter()
+                .flat_map(|tags| tags.split(','))
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect(),
+            since: params.since,
+            until: params.until,
+        }
+    }
+}
+
+fn default_size() -> usize {
+    10
+}
+
+/// `GET /api/search?q=...`, hybrid mode also reads the `RrfParams` fields
+async fn search_docs(
+    State(state): State<AppState>,
+    Query(params): Query<SearchParams>,
+    Query(filters): Query<FilterParams>,
+    Query(rrf): Query<RrfParams>,
+) -> ApiResult<Json<SearchResults>> {
+    let filters = Filters::from(filters);
+    rrf.validate()?;
+    let mode = match (params.mode, &state.embedder) {
+        (Some(mode), _) => mode,
+        (None, Some(_)) => SearchMode::Hybrid,
+        (None, None) => SearchMode::Text,
+    };
+
+    let (client, index) = (&state.client, state.index.as_str());
+    let results = match mode {
+        SearchMode::Text => {
+            let query = SearchQuery {
+                text: params.q,
+                filters,
+                date_interval: None,
+                from: params.from as i64,
+                size: params.size as i64,
+                search_after: None,
+            };
+            search::search(client, index, &query).await?
+        }
+        SearchMode::Vector => {
+            let query = KnnQuery {
+                vector: embed_query(&state, &params.q).await?,
+                k: params.size,
+                num_candidates: None,
+                filters,
+            };
+            search::knn(client, index, &query).await?
+        }
+        SearchMode::Hybrid => {
+            let query = HybridQuery {
+                vector: embed_query(&state, &params.q).await?,
+                text: params.q,
+                filters,
+                size: params.size,
+                window: None,
+                rrf,
+            };
+            search::hybrid(client, index, &query).await?
+        }
+    };
+    Ok(Json(results))
+}
+
+#[derive(Deserialize)]
+struct ContextParams {
+    q: String,
+    max_tokens: Option<usize>,
+}
+
+/// `GET /api/context?q=...&max_tokens=...`, the best sections that fit the budget
+async fn context_docs(
+    State(state): State<AppState>,
+    Query(params): Query<ContextParams>,
+    Query(filters): Query<FilterParams>,
+    Query(rrf): Query<RrfParams>,
+) -> ApiResult<Json<context::Context>> {
+    rrf.validate()?;
+    let query = ContextQuery {
+        text: params.q,
+        filters: filters.into(),
+        max_tokens: params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        rrf,
+    };
+    let context = context::retrieve(
+        &state.client,
+        &state.index,
+        state.embedder.as_deref(),
+        state.tokenizer.as_ref(),
+        &query,
+    )
+    .await?;
+    Ok(Json(context))
+}
+
+async fn embed_query(state: &AppState, text: &str) -> ApiResult<Vec<f32>> {
+    let Some(embedder) = &state.embedder else {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("No embedder is configured, use mode=text"),
+        ));
+    };
+    Ok(embed::embed_query(embedder.as_ref(), text).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        config::{EmbedderKind, EmbeddingConfig},
+        pool::tests::{closed_node, config, mock_server},
+        tokenizer,
+    };
+
+    /// State whose Elasticsearch cannot be reached
+    async fn state(ingest_root: Option<PathBuf>) -> AppState {
+        state_on(&closed_node().await, ingest_root).await
+    }
+
+    async fn state_on(node: &str, ingest_root: Option<PathBuf>) -> AppState {
+        let config = config(&[node]);
+        AppState {
+            client: EsClient::connect(&config).await.unwrap(),
+            index: "docs".to_string(),
+            embedder: None,
+            tokenizer: tokenizer::from_name("chars").unwrap(),
+            store: MetadataStore::connect("sqlite::memory:").await.unwrap(),
+            ingest_root,
+            ingesting: Arc::default(),
+        }
+    }
+
+    async fn send(state: AppState, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post_ingest(body: serde_json::Value) -> Request<Body> {
+        Request::post("/api/repositories")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unreachable_elasticsearch_is_a_bad_gateway() {
+        let (status, body) = send(state(None).await, get("/api/search?q=tokio")).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn vector_search_without_an_embedder_is_a_bad_request() {
+        let (status, body) = send(state(None).await, get("/api/search?q=tokio&mode=vector")).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("No embedder"));
+    }
+
+    #[tokio::test]
+    async fn invalid_rrf_params_are_a_bad_request() {
+        let uris = [
+            "/api/search?q=tokio&rank_constant=0",
+            "/api/search?q=tokio&lexical_weight=-1",
+            "/api/context?q=tokio&rank_constant=-60",
+            "/api/context?q=tokio&vector_weight=-0.5",
+        ];
+        for uri in uris {
+            let (status, body) = send(state(None).await, get(uri)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert!(
+                body["error"].as_str().unwrap().contains("must be"),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_searches_are_a_bad_request() {
+        let uris = [
+            "/api/search?q=tokio&mode=vector&size=0",
+            "/api/search?q=tokio&mode=vector&size=100000",
+            "/api/search?q=tokio&mode=hybrid&size=0",
+            "/api/search?q=tokio&mode=hybrid&size=100000",
+        ];
+        let hashing = EmbeddingConfig {
+            kind: EmbedderKind::Hashing,
+            dims: 8,
+            batch_size: 10,
+        };
+        for uri in uris {
+            let state = AppState {
+                embedder: Some(embed::from_config(&hashing).unwrap()),
+                ..state(None).await
+            };
+            let (status, body) = send(state, get(uri)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert!(
+                body["error"].as_str().unwrap().contains("must be"),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_libraries_and_versions_are_client_errors() {
+        let (node, requests) = mock_server(|_| {
+            let libraries = json!([
+                { "key": "tokio", "versions": { "buckets": [{ "key": "1.0.0" }] } },
+                { "key": "notes", "versions": { "buckets": [] } }
+            ]);
+            let body = json!({ "aggregations": { "libraries": { "buckets": libraries } } });
+            (200, body.to_string())
+        })
+        .await;
+
+        let cases = [
+            ("/api/search?q=spawn&library=axum", StatusCode::NOT_FOUND),
+            (
+                "/api/search?q=spawn&library=notes&version=1.x",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/api/search?q=spawn&library=tokio&version=2.x",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/api/search?q=spawn&library=tokio&version=not-a-req!",
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (uri, expected) in cases {
+            let (status, _) = send(state_on(&node, None).await, get(uri)).await;
+            assert_eq!(status, expected, "{}", uri);
+        }
+        // Every case fails on the version lookup, before the search itself
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), cases.len());
+        for request in requests.iter() {
+            assert_eq!(request.method, "POST");
+            assert!(request.path.starts_with("/docs/_search"));
+            assert!(request.json()["aggs"]["libraries"].is_object());
+        }
+    }
+
+    #[tokio::test]
+    async fn ingestion_is_disabled_without_a_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let request = post_ingest(json!({ "path": dir.path() }));
+        let (status, _) = send(state(None).await, request).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn ingested_paths_must_be_directories_inside_the_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        let root = root.path().canonicalize().unwrap();
+
+        let cases = [
+            (json!({ "path": outside.path() }), StatusCode::FORBIDDEN),
+            (json!({ "path": "docs/../.." }), StatusCode::FORBIDDEN),
+            (json!({ "path": "missing" }), StatusCode::BAD_REQUEST),
+        ];
+        for (body, expected) in cases {
+            let state = state(Some(root.clone())).await;
+            let (status, _) = send(state, post_ingest(body.clone())).await;
+            assert_eq!(status, expected, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn repositories_being_ingested_are_a_conflict() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        let state = state(Some(root.path().canonicalize().unwrap())).await;
+        let _running = state.start_ingest("docs").unwrap();
+
+        let request = post_ingest(json!({ "path": "docs" }));
+        let (status, body) = send(state.clone(), request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"].as_str().unwrap().contains("already"));
+
+        let request = Request::delete("/api/repositories/docs")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(state, request).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn finished_ingestions_release_the_repository() {
+        let state = state(None).await;
+        drop(state.start_ingest("docs").unwrap());
+
+        assert!(!state.is_ingesting("docs"));
+        assert!(state.start_ingest("docs").is_some());
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{Result, bail};
+use tiktoken_rs::CoreBPE;
+
+/// Tokenizer names accepted by `from_name`
+pub const TOKENIZERS: &[&str] = &["chars", "cl100k_base", "o200k_base"];
+pub const DEFAULT_TOKENIZER: &str = "chars";
+
+/// Characters per token assumed by the `chars` estimate, about right for
+/// English prose and code with OpenAI style tokenizers
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Counts the tokens a text takes up in a model's context window
+pub trait Tokenizer: fmt::Debug + Send + Sync {
+    fn count(&self, text: &str) -> usize;
+}
+
+/// Estimate from the number of characters, needs no vocabulary
+#[derive(Debug)]
+struct CharEstimate;
+
+impl Tokenizer for CharEstimate {
+    fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(CHARS_PER_TOKEN)
+    }
+}
+
+/// Exact count for OpenAI models
+struct Bpe(CoreBPE);
+
+impl fmt::Debug for Bpe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The vocabulary is far too long to print
+        f.write_str("Bpe")
+    }
+}
+
+impl Tokenizer for Bpe {
+    fn count(&self, text: &str) -> usize {
+        self.0.encode_ordinary(text).len()
+    }
+}
+
+pub fn from_name(name: &str) -> Result<Arc<dyn Tokenizer>> {
+    let tokenizer: Arc<dyn Tokenizer> = match name {
+        "chars" => Arc::new(CharEstimate),
+        "cl100k_base" => Arc::new(Bpe(tiktoken_rs::cl100k_base()?)),
+        "o200k_base" => Arc::new(Bpe(tiktoken_rs::o200k_base()?)),
+        _ => bail!(
+            "Unknown tokenizer '{}', expected one of {}",
+            name,
+            TOKENIZERS.join(", ")
+        ),
+    };
+    Ok(tokenizer)
+}
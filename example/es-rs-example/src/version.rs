@@ -0,0 +1,271 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use anyhow::{Context, Result, bail};
+use elasticsearch::SearchParts;
+use semver::{Version, VersionReq};
+use serde_json::{Value, json};
+
+use crate::{
+    client::EsClient,
+    error::{self, EsError, InvalidRequest},
+    retry::Operation,
+};
+
+/// Upper bound on the libraries and versions returned by one aggregation
+const MAX_BUCKETS: usize = 1000;
+
+/// `1.2.3` or `v1.2.3`
+pub fn parse(version: &str) -> Option<Version> {
+    Version::parse(version.strip_prefix('v').unwrap_or(version)).ok()
+}
+
+/// Version a git ref stands for, `v1.2.0` -> `1.2.0`. Refs that are not a
+/// semantic version, such as branches, have none.
+pub fn from_ref(git_ref: &str) -> Option<String> {
+    parse(git_ref).map(|version| version.to_string())
+}
+
+/// Newest first. Semantic versions come before anything else, e.g. `main`.
+pub fn sort(versions: &mut [String]) {
+    versions.sort_by(|a, b| match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => b.cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => a.cmp(b),
+    });
+}
+
+/// The newest release, or the newest pre-release when there is no release
+pub fn latest(versions: &[String]) -> Option<&str> {
+    let parsed = || {
+        versions
+            .iter()
+            .filter_map(|version| parse(version).map(|parsed| (parsed, version)))
+    };
+    parsed()
+        .filter(|(parsed, _)| parsed.pre.is_empty())
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .or_else(|| parsed().max_by(|a, b| a.0.cmp(&b.0)))
+        .map(|(_, version)| version.as_str())
+        .or_else(|| versions.first().map(String::as_str))
+}
+
+/// Pick the version `spec` asks for: an exact version, or the newest one
+/// matching a requirement such as `1.x`, `~1.2` or `>=1.0, <2.0`.
+/// Without a spec the latest version is picked.
+pub fn select<'a>(versions: &'a [String], spec: Option<&str>) -> Result<Option<&'a str>> {
+    let Some(spec) = spec else {
+        return Ok(latest(versions));
+    };
+
+    let exact = versions
+        .iter()
+        .find(|version| *version == spec || parse(version).is_some_and(|v| Some(v) == parse(spec)));
+    if let Some(version) = exact {
+        return Ok(Some(version));
+    }
+
+    let requirement = VersionReq::parse(spec.strip_prefix('v').unwrap_or(spec)).map_err(|err| {
+        InvalidRequest(format!("Invalid version requirement '{}': {}", spec, err))
+    })?;
+    Ok(versions
+        .iter()
+        .filter_map(|version| parse(version).map(|parsed| (parsed, version)))
+        .filter(|(parsed, _)| requirement.matches(parsed))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, version)| version.as_str()))
+}
+
+/// Versions indexed for every library, or only for `library`, newest first.
+/// Libraries ingested without a version map to an empty list.
+pub async fn list(
+    client: &EsClient,
+    index_name: &str,
+    library: Option<&str>,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let query = match library {
+        Some(library) => json!({ "term": { "repo": library } }),
+        None => json!({ "match_all": {} }),
+    };
+    let body = json!({
+        "size": 0,
+        "query": query,
+        "aggs": {
+            "libraries": {
+                "terms": { "field": "repo", "size": MAX_BUCKETS },
+                "aggs": {
+                    "versions": { "terms": { "field": "version", "size": MAX_BUCKETS } }
+                }
+            }
+        }
+    });
+    let body = &body;
+
+    let response = client
+        .send(Operation::Read, |es| async move {
+            es.search(SearchParts::Index(&[index_name]))
+                .body(body)
+                .send()
+                .await
+        })
+        .await?;
+    let response = error::check(response)
+        .await
+        .context("Failed to list versions")?;
+
+    let body: Value = response.json().await?;
+    let mut libraries = BTreeMap::new();
+    for bucket in buckets(&body["aggregations"]["libraries"]) {
+        let Some(library) = bucket["key"].as_str() else {
+            continue;
+        };
+        let mut versions: Vec<String> = buckets(&bucket["versions"])
+            .filter_map(|version| version["key"].as_str().map(str::to_string))
+            .collect();
+        sort(&mut versions);
+        libraries.insert(library.to_string(), versions);
+    }
+
+    Ok(libraries)
+}
+
+fn buckets(aggregation: &Value) -> impl Iterator<Item = &Value> {
+    aggregation["buckets"].as_array().into_iter().flatten()
+}
+
+/// Filter restricting a search to one version per library: the version
+/// `spec` selects, the latest one by default. Libraries without a matching
+/// version are left out, which is an error when `library` is given.
+pub async fn filter(
+    client: &EsClient,
+    index_name: &str,
+    library: Option<&str>,
+    spec: Option<&str>,
+) -> Result<Value> {
+    let libraries = list(client, index_name, library).await?;
+    if let Some(library) = library
+        && !libraries.contains_key(library)
+    {
+        bail!(EsError::not_found(format!(
+            "Library '{}' is not indexed in '{}'",
+            library, index_name
+        )));
+    }
+
+    let mut clauses = Vec::new();
+    for (name, versions) in &libraries {
+        if versions.is_empty() {
+            match spec {
+                None => clauses.push(json!({ "term": { "repo": name } })),
+                Some(_) if library.is_some() => {
+                    bail!(InvalidRequest(format!(
+                        "Library '{}' was indexed without versions",
+                        name
+                    )))
+                }
+                Some(_) => {}
+            }
+            continue;
+        }
+        match select(versions, spec)? {
+            Some(version) => clauses.push(json!({
+                "bool": {
+                    "filter": [
+                        { "term": { "repo": name } },
+                        { "term": { "version": version } }
+                    ]
+                }
+            })),
+            None if library.is_some() => bail!(InvalidRequest(format!(
+                "No version of '{}' matches '{}', available: {}",
+                name,
+                spec.unwrap_or_default(),
+                versions.join(", ")
+            ))),
+            None => {}
+        }
+    }
+
+    Ok(json!({ "bool": { "should": clauses, "minimum_should_match": 1 } }))
+}
+
+/// Print the versions of every library, newest first
+pub async fn print(client: &EsClient, index_name: &str, library: Option<&str>) -> Result<()> {
+    let libraries = list(client, index_name, library).await?;
+    if libraries.is_empty() {
+        println!("No libraries indexed in '{}'", index_name);
+    }
+    for (name, versions) in &libraries {
+        if versions.is_empty() {
+            println!("{}: (unversioned)", name);
+        } else {
+            println!("{}: {}", name, versions.join(", "));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(list: &[&str]) -> Vec<String> {
+        list.iter().map(|version| version.to_string()).collect()
+    }
+
+    #[test]
+    fn sort_puts_newest_semver_first() {
+        let mut list = versions(&["main", "v1.2.0", "1.10.0", "2.0.0-rc.1", "1.9.3", "dev"]);
+        sort(&mut list);
+        assert_eq!(
+            list,
+            ["2.0.0-rc.1", "1.10.0", "1.9.3", "v1.2.0", "dev", "main"]
+        );
+    }
+
+    #[test]
+    fn latest_prefers_releases_over_pre_releases() {
+        let list = versions(&["1.9.3", "2.0.0-rc.1", "1.10.0"]);
+        assert_eq!(latest(&list), Some("1.10.0"));
+
+        let list = versions(&["2.0.0-beta.1", "2.0.0-rc.1"]);
+        assert_eq!(latest(&list), Some("2.0.0-rc.1"));
+
+        let list = versions(&["main"]);
+        assert_eq!(latest(&list), Some("main"));
+        assert_eq!(latest(&[]), None);
+    }
+
+    #[test]
+    fn select_matches_exact_versions_and_requirements() {
+        let list = versions(&["v1.2.0", "1.9.3", "1.10.0", "2.0.0-rc.1", "2.1.0", "main"]);
+
+        assert_eq!(select(&list, None).unwrap(), Some("2.1.0"));
+        assert_eq!(select(&list, Some("main")).unwrap(), Some("main"));
+        assert_eq!(select(&list, Some("1.2.0")).unwrap(), Some("v1.2.0"));
+        assert_eq!(select(&list, Some("1.x")).unwrap(), Some("1.10.0"));
+        assert_eq!(select(&list, Some("~1.9")).unwrap(), Some("1.9.3"));
+        assert_eq!(select(&list, Some(">=1.0, <1.5")).unwrap(), Some("v1.2.0"));
+        assert_eq!(select(&list, Some("3.x")).unwrap(), None);
+    }
+
+    #[test]
+    fn select_skips_pre_releases_unless_asked_for() {
+        let list = versions(&["1.0.0", "2.0.0-rc.1"]);
+
+        assert_eq!(select(&list, Some("2.x")).unwrap(), None);
+        assert_eq!(
+            select(&list, Some("2.0.0-rc.1")).unwrap(),
+            Some("2.0.0-rc.1")
+        );
+        assert_eq!(
+            select(&list, Some(">=2.0.0-rc.0")).unwrap(),
+            Some("2.0.0-rc.1")
+        );
+    }
+
+    #[test]
+    fn select_rejects_invalid_requirements() {
+        assert!(select(&versions(&["1.0.0"]), Some("not a version")).is_err());
+    }
+}